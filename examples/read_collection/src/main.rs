@@ -13,6 +13,7 @@ pub fn main() -> PolarsResult<()> {
         collection,
         infer_schema_length: Some(1000),
         n_rows: Some(129),
+        ..Default::default()
     })?
     .collect()?;
 
@@ -0,0 +1,24 @@
+use mongodb::bson::doc;
+use mongodb::options::{AuthMechanism, Credential};
+
+/// Builds a `MONGODB-AWS` credential for Atlas clusters using AWS IAM authentication, e.g.
+/// with the temporary credentials of an assumed role.
+///
+/// Requires the `aws-auth` feature, which enables the driver's `aws-auth` feature.
+/// Without explicit keys, the driver reads them from the `AWS_ACCESS_KEY_ID`,
+/// `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN` environment variables or from the
+/// instance metadata.
+pub fn aws_credential(
+    access_key_id: Option<String>,
+    secret_access_key: Option<String>,
+    session_token: Option<String>,
+) -> Credential {
+    let mut credential = Credential::default();
+    credential.mechanism = Some(AuthMechanism::MongoDbAws);
+    credential.username = access_key_id;
+    credential.password = secret_access_key;
+    credential.source = Some("$external".into());
+    credential.mechanism_properties =
+        session_token.map(|token| doc! { "AWS_SESSION_TOKEN": token });
+    credential
+}
@@ -1,5 +1,6 @@
 use crate::conversion::*;
-use mongodb::bson::Bson;
+use crate::{InvalidValuePolicy, OutOfRangePolicy, StructNullPolicy};
+use mongodb::bson::{Bson, DateTime};
 use num::traits::NumCast;
 use polars::export::arrow::types::NativeType;
 use polars::prelude::*;
@@ -7,7 +8,7 @@ use polars::prelude::*;
 pub(crate) fn init_buffers(
     schema: &polars::prelude::Schema,
     capacity: usize,
-) -> PolarsResult<PlIndexMap<String, Buffer>> {
+) -> PolarsResult<PlIndexMap<String, Buffer<'_>>> {
     schema
         .iter()
         .map(|(name, dtype)| {
@@ -19,13 +20,18 @@ pub(crate) fn init_buffers(
                 DataType::UInt64 => Buffer::UInt64(PrimitiveChunkedBuilder::new(name, capacity)),
                 DataType::Float32 => Buffer::Float32(PrimitiveChunkedBuilder::new(name, capacity)),
                 DataType::Float64 => Buffer::Float64(PrimitiveChunkedBuilder::new(name, capacity)),
-                DataType::Utf8 => {
+                // polars strings always use 64 bit offsets, so huge fields can't overflow them.
+                // categoricals are read as strings and cast after the partitions are combined.
+                DataType::Utf8 | DataType::Categorical(_) => {
                     Buffer::Utf8(Utf8ChunkedBuilder::new(name, capacity, capacity * 5))
                 }
                 DataType::Datetime(_, _) => {
                     Buffer::Datetime(PrimitiveChunkedBuilder::new(name, capacity))
                 }
                 DataType::Date => Buffer::Date(PrimitiveChunkedBuilder::new(name, capacity)),
+                DataType::Struct(fields) => {
+                    Buffer::Struct((Vec::with_capacity(capacity), name, fields.as_slice()))
+                }
                 _ => Buffer::All((Vec::with_capacity(capacity), name)),
             };
             Ok((name.clone(), builder))
@@ -33,6 +39,53 @@ pub(crate) fn init_buffers(
         .collect()
 }
 
+/// Options controlling how bson values are added to the buffers.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ParseOptions {
+    pub(crate) invalid_values: InvalidValuePolicy,
+    pub(crate) out_of_range_datetimes: OutOfRangePolicy,
+    pub(crate) struct_null_policy: StructNullPolicy,
+    /// read `""` as null in string columns.
+    pub(crate) empty_string_as_null: bool,
+}
+
+impl ParseOptions {
+    /// The milliseconds of a datetime, after applying the out of range policy.
+    fn datetime_millis(&self, dt: &DateTime) -> PolarsResult<Option<i64>> {
+        let ms = dt.timestamp_millis();
+        if (MIN_DATETIME_MS..=MAX_DATETIME_MS).contains(&ms) {
+            return Ok(Some(ms));
+        }
+        match self.out_of_range_datetimes {
+            OutOfRangePolicy::Clamp => Ok(Some(ms.clamp(MIN_DATETIME_MS, MAX_DATETIME_MS))),
+            OutOfRangePolicy::Null => Ok(None),
+            OutOfRangePolicy::Error => Err(PolarsError::ComputeError(
+                format!(
+                    "datetime {} ms from the epoch is out of the range polars can represent",
+                    ms
+                )
+                .into(),
+            )),
+        }
+    }
+
+    /// Applies the invalid value policy to the result of a conversion.
+    fn resolve<T>(
+        &self,
+        converted: Result<Option<T>, ()>,
+        value: &Bson,
+        dtype: &DataType,
+    ) -> PolarsResult<Option<T>> {
+        match (converted, self.invalid_values) {
+            (Ok(v), _) => Ok(v),
+            (Err(_), InvalidValuePolicy::Null) => Ok(None),
+            (Err(_), InvalidValuePolicy::Error) => Err(PolarsError::ComputeError(
+                format!("{} can't be converted to {} without loss", value, dtype).into(),
+            )),
+        }
+    }
+}
+
 #[allow(clippy::large_enum_variant)]
 pub(crate) enum Buffer<'a> {
     Boolean(BooleanChunkedBuilder),
@@ -46,6 +99,8 @@ pub(crate) enum Buffer<'a> {
     Datetime(PrimitiveChunkedBuilder<Int64Type>),
     Date(PrimitiveChunkedBuilder<Int32Type>),
     All((Vec<AnyValue<'a>>, &'a str)),
+    /// the values of a struct column, with the fields of its schema.
+    Struct((Vec<AnyValue<'a>>, &'a str, &'a [Field])),
 }
 
 impl<'a> Buffer<'a> {
@@ -65,7 +120,7 @@ impl<'a> Buffer<'a> {
                 .unwrap(),
             Buffer::Date(v) => v.finish().into_series().cast(&DataType::Date).unwrap(),
             Buffer::Utf8(v) => v.finish().into_series(),
-            Buffer::All((vals, name)) => Series::new(name, vals),
+            Buffer::All((vals, name)) | Buffer::Struct((vals, name, _)) => Series::new(name, vals),
         };
         Ok(s)
     }
@@ -82,10 +137,23 @@ impl<'a> Buffer<'a> {
             Buffer::Utf8(v) => v.append_null(),
             Buffer::Datetime(v) => v.append_null(),
             Buffer::Date(v) => v.append_null(),
-            Buffer::All((v, _)) => v.push(AnyValue::Null),
+            Buffer::All((v, _)) | Buffer::Struct((v, _, _)) => v.push(AnyValue::Null),
         };
     }
-    pub(crate) fn add(&mut self, value: &Bson) -> PolarsResult<()> {
+    /// Whether `value` can be added without loss, i.e. without the invalid value policy
+    /// applying to it.
+    pub(crate) fn accepts(&self, value: &Bson) -> bool {
+        use Buffer::*;
+        match self {
+            Int32(_) => deserialize_int::<i32>(value).is_ok(),
+            Int64(_) => deserialize_int::<i64>(value).is_ok(),
+            UInt64(_) => deserialize_int::<u64>(value).is_ok(),
+            UInt32(_) => deserialize_int::<u32>(value).is_ok(),
+            _ => true,
+        }
+    }
+
+    pub(crate) fn add(&mut self, value: &Bson, opts: &ParseOptions) -> PolarsResult<()> {
         use Buffer::*;
         match self {
             Boolean(buf) => {
@@ -96,35 +164,23 @@ impl<'a> Buffer<'a> {
                 Ok(())
             }
             Int32(buf) => {
-                let n = deserialize_number::<i32>(value);
-                match n {
-                    Some(v) => buf.append_value(v),
-                    None => buf.append_null(),
-                }
+                let n = opts.resolve(deserialize_int::<i32>(value), value, &DataType::Int32)?;
+                buf.append_option(n);
                 Ok(())
             }
             Int64(buf) => {
-                let n = deserialize_number::<i64>(value);
-                match n {
-                    Some(v) => buf.append_value(v),
-                    None => buf.append_null(),
-                }
+                let n = opts.resolve(deserialize_int::<i64>(value), value, &DataType::Int64)?;
+                buf.append_option(n);
                 Ok(())
             }
             UInt64(buf) => {
-                let n = deserialize_number::<u64>(value);
-                match n {
-                    Some(v) => buf.append_value(v),
-                    None => buf.append_null(),
-                }
+                let n = opts.resolve(deserialize_int::<u64>(value), value, &DataType::UInt64)?;
+                buf.append_option(n);
                 Ok(())
             }
             UInt32(buf) => {
-                let n = deserialize_number::<u32>(value);
-                match n {
-                    Some(v) => buf.append_value(v),
-                    None => buf.append_null(),
-                }
+                let n = opts.resolve(deserialize_int::<u32>(value), value, &DataType::UInt32)?;
+                buf.append_option(n);
                 Ok(())
             }
             Float32(buf) => {
@@ -148,22 +204,39 @@ impl<'a> Buffer<'a> {
                 match value {
                     Bson::RegularExpression(r) => buf.append_value(r.to_string()),
                     Bson::ObjectId(oid) => buf.append_value(oid.to_hex()),
+                    Bson::Binary(b) => buf.append_option(uuid_to_string(b)),
                     Bson::JavaScriptCode(v) => buf.append_value(v),
+                    Bson::JavaScriptCodeWithScope(v) => buf.append_value(&v.code),
+                    Bson::String(v) if v.is_empty() && opts.empty_string_as_null => {
+                        buf.append_null()
+                    }
                     Bson::String(v) => buf.append_value(v),
                     Bson::Document(doc) => buf.append_value(doc.to_string()),
                     Bson::Array(arr) => buf.append_value(format!("{:#?}", arr)),
                     Bson::Symbol(s) => buf.append_value(s),
+                    // found in the `config` database, e.g. `lastmod` and the bounds of chunks.
+                    Bson::Timestamp(ts) => buf.append_value(ts.to_string()),
+                    Bson::MinKey | Bson::MaxKey => buf.append_value(value.to_string()),
                     _ => buf.append_null(),
                 }
                 Ok(())
             }
             Datetime(buf) => {
-                let v = deserialize_date::<i64>(value);
+                let v = match value {
+                    Bson::DateTime(dt) => opts.datetime_millis(dt)?,
+                    _ => deserialize_date::<i64>(value),
+                };
                 buf.append_option(v);
                 Ok(())
             }
             Date(buf) => {
-                let v = deserialize_date::<i32>(value);
+                let v = match value {
+                    // days since the epoch, the time of day is dropped.
+                    Bson::DateTime(dt) => opts
+                        .datetime_millis(dt)?
+                        .and_then(|ms| i32::try_from(ms.div_euclid(MS_PER_DAY)).ok()),
+                    _ => deserialize_date::<i32>(value),
+                };
                 buf.append_option(v);
                 Ok(())
             }
@@ -172,12 +245,27 @@ impl<'a> Buffer<'a> {
                 buf.push(av.0);
                 Ok(())
             }
+            Struct((buf, _, fields)) => {
+                let av = match value {
+                    Bson::Document(doc)
+                        if opts.struct_null_policy == StructNullPolicy::AllOrNothing
+                            && fields.iter().any(|field| !doc.contains_key(field.name())) =>
+                    {
+                        AnyValue::Null
+                    }
+                    Bson::Document(doc) => document_to_struct(doc, fields),
+                    value => Wrap::<AnyValue>::from(value).0,
+                };
+                buf.push(av);
+                Ok(())
+            }
         }
     }
 }
 fn deserialize_float<T: NativeType + NumCast>(value: &Bson) -> Option<T> {
     match value {
         Bson::Double(num) => num::traits::cast::<f64, T>(*num),
+        Bson::Decimal128(d) => num::traits::cast::<f64, T>(decimal128_to_f64(d)),
         Bson::Int32(num) => num::traits::cast::<i32, T>(*num),
         Bson::Int64(num) => num::traits::cast::<i64, T>(*num),
         Bson::Boolean(b) => num::traits::cast::<i32, T>(*b as i32),
@@ -185,14 +273,18 @@ fn deserialize_float<T: NativeType + NumCast>(value: &Bson) -> Option<T> {
     }
 }
 
-fn deserialize_number<T: NativeType + NumCast>(value: &Bson) -> Option<T> {
-    match value {
+/// Converts a bson number to an integer. Non numeric values are null, numbers that can't be
+/// represented without loss (`42.5`, `1e30`, an `Int64` overflowing an `Int32`) are an `Err`.
+fn deserialize_int<T: NativeType + NumCast>(value: &Bson) -> Result<Option<T>, ()> {
+    let n = match value {
+        Bson::Double(num) if num.fract() != 0.0 => return Err(()),
         Bson::Double(num) => num::traits::cast::<f64, T>(*num),
         Bson::Int32(num) => num::traits::cast::<i32, T>(*num),
         Bson::Int64(num) => num::traits::cast::<i64, T>(*num),
         Bson::Boolean(b) => num::traits::cast::<i32, T>(*b as i32),
-        _ => None,
-    }
+        _ => return Ok(None),
+    };
+    n.map(Some).ok_or(())
 }
 
 fn deserialize_date<T: NativeType + NumCast>(value: &Bson) -> Option<T> {
@@ -205,3 +297,183 @@ fn deserialize_date<T: NativeType + NumCast>(value: &Bson) -> Option<T> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    fn opts(out_of_range_datetimes: OutOfRangePolicy) -> ParseOptions {
+        ParseOptions {
+            invalid_values: InvalidValuePolicy::Null,
+            out_of_range_datetimes,
+            struct_null_policy: StructNullPolicy::PerField,
+            empty_string_as_null: false,
+        }
+    }
+
+    /// Reads `values` into a column of `dtype`.
+    fn read(dtype: DataType, values: &[Bson], opts: &ParseOptions) -> PolarsResult<Series> {
+        let schema = Schema::from(vec![Field::new("a", dtype)].into_iter());
+        let mut buffers = init_buffers(&schema, values.len())?;
+        let buffer = buffers.get_mut("a").unwrap();
+        for value in values {
+            buffer.add(value, opts)?;
+        }
+        buffers.swap_remove("a").unwrap().into_series()
+    }
+
+    #[test]
+    fn doubles_are_read_as_integers_without_loss_only() {
+        assert_eq!(deserialize_int::<i64>(&Bson::Double(42.0)), Ok(Some(42)));
+        assert_eq!(deserialize_int::<i32>(&Bson::Double(-42.0)), Ok(Some(-42)));
+        assert_eq!(deserialize_int::<i64>(&Bson::Double(42.5)), Err(()));
+        assert_eq!(deserialize_int::<i64>(&Bson::Double(1e30)), Err(()));
+        assert_eq!(deserialize_int::<u32>(&Bson::Double(-1.0)), Err(()));
+        assert_eq!(deserialize_int::<i32>(&Bson::Int64(1 << 40)), Err(()));
+        assert_eq!(deserialize_int::<i32>(&Bson::String("42".into())), Ok(None));
+
+        // the invalid value policy decides what lossy values are read as.
+        let values = [Bson::Double(42.0), Bson::Double(42.5), Bson::Double(1e30)];
+        let s = read(DataType::Int64, &values, &opts(OutOfRangePolicy::Null)).unwrap();
+        let ints: Vec<_> = s.i64().unwrap().into_iter().collect();
+        assert_eq!(ints, [Some(42), None, None]);
+        let strict = ParseOptions {
+            invalid_values: InvalidValuePolicy::Error,
+            ..opts(OutOfRangePolicy::Null)
+        };
+        assert!(read(DataType::Int64, &values, &strict).is_err());
+    }
+
+    #[test]
+    fn out_of_range_datetimes_follow_the_policy() {
+        let dtype = DataType::Datetime(TimeUnit::Milliseconds, None);
+        let values = [
+            Bson::DateTime(DateTime::from_millis(0)),
+            Bson::DateTime(DateTime::MAX),
+            Bson::DateTime(DateTime::MIN),
+        ];
+        let millis = |s: Series| s.datetime().unwrap().into_iter().collect::<Vec<_>>();
+
+        let s = read(dtype.clone(), &values, &opts(OutOfRangePolicy::Clamp)).unwrap();
+        assert_eq!(
+            millis(s),
+            [Some(0), Some(MAX_DATETIME_MS), Some(MIN_DATETIME_MS)]
+        );
+        let s = read(dtype.clone(), &values, &opts(OutOfRangePolicy::Null)).unwrap();
+        assert_eq!(millis(s), [Some(0), None, None]);
+        assert!(read(dtype, &values, &opts(OutOfRangePolicy::Error)).is_err());
+    }
+
+    #[test]
+    fn out_of_range_dates_follow_the_policy() {
+        let values = [
+            Bson::DateTime(DateTime::from_millis(MS_PER_DAY + 1)),
+            Bson::DateTime(DateTime::MAX),
+        ];
+        let s = read(DataType::Date, &values, &opts(OutOfRangePolicy::Null)).unwrap();
+        let days: Vec<_> = s.date().unwrap().into_iter().collect();
+        assert_eq!(days, [Some(1), None]);
+    }
+
+    #[test]
+    fn empty_strings_are_read_as_null_only_when_asked() {
+        let values = [
+            Bson::String("a".into()),
+            Bson::String("".into()),
+            Bson::String(" ".into()),
+            Bson::Null,
+        ];
+        let strings = |opts: &ParseOptions| {
+            let s = read(DataType::Utf8, &values, opts).unwrap();
+            let strings: Vec<_> = s
+                .utf8()
+                .unwrap()
+                .into_iter()
+                .map(|v| v.map(String::from))
+                .collect();
+            (strings, s.null_count())
+        };
+        let some = |v: &str| Some(v.to_string());
+        assert_eq!(
+            strings(&opts(OutOfRangePolicy::Null)),
+            (vec![some("a"), some(""), some(" "), None], 1)
+        );
+        let empty_as_null = ParseOptions {
+            empty_string_as_null: true,
+            ..opts(OutOfRangePolicy::Null)
+        };
+        assert_eq!(
+            strings(&empty_as_null),
+            (vec![some("a"), None, some(" "), None], 2)
+        );
+    }
+
+    #[test]
+    fn struct_null_policy_decides_on_subdocuments_lacking_fields() {
+        let fields = vec![
+            Field::new("x", DataType::Int64),
+            Field::new("y", DataType::Utf8),
+        ];
+        let values = [
+            Bson::Document(doc! { "x": 1_i64, "y": "a" }),
+            Bson::Document(doc! { "x": 2_i64 }),
+            Bson::Null,
+        ];
+        // the values of each field, polars 0.24 has no validity on the struct itself.
+        let read_fields = |struct_null_policy| {
+            let opts = ParseOptions {
+                struct_null_policy,
+                ..opts(OutOfRangePolicy::Null)
+            };
+            let s = read(DataType::Struct(fields.clone()), &values, &opts).unwrap();
+            let s = s.struct_().unwrap();
+            let x: Vec<_> = s.fields()[0].i64().unwrap().into_iter().collect();
+            let y: Vec<_> = s.fields()[1]
+                .utf8()
+                .unwrap()
+                .into_iter()
+                .map(|v| v.map(String::from))
+                .collect();
+            (x, y)
+        };
+        let a = Some("a".to_string());
+        assert_eq!(
+            read_fields(StructNullPolicy::PerField),
+            (vec![Some(1), Some(2), None], vec![a.clone(), None, None])
+        );
+        assert_eq!(
+            read_fields(StructNullPolicy::AllOrNothing),
+            (vec![Some(1), None, None], vec![a, None, None])
+        );
+    }
+
+    #[test]
+    fn null_array_elements_are_null_entries_of_the_list() {
+        let values = [
+            Bson::Array(vec![Bson::Int64(1), Bson::Null, Bson::Int64(3)]),
+            Bson::Null,
+            Bson::Array(vec![Bson::Null]),
+        ];
+        // the type is inferred from the elements that aren't null.
+        let dtype = Wrap::<DataType>::from(&values[0]).0;
+        assert_eq!(dtype, DataType::List(Box::new(DataType::Int64)));
+        assert_eq!(
+            Wrap::<DataType>::from(&values[2]).0,
+            DataType::List(Box::new(DataType::Null))
+        );
+
+        let s = read(dtype, &values, &opts(OutOfRangePolicy::Null)).unwrap();
+        assert_eq!(s.null_count(), 1);
+        let lists: Vec<_> = s
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|list| list.map(|list| list.i64().unwrap().into_iter().collect::<Vec<_>>()))
+            .collect();
+        assert_eq!(
+            lists,
+            [Some(vec![Some(1), None, Some(3)]), None, Some(vec![None])]
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use mongodb::bson::{doc, Document};
+use mongodb::options::AggregateOptions;
+use mongodb::sync::{Collection, Database};
+use polars::prelude::*;
+
+use crate::error::mongo_error;
+
+/// The kind of data store backing a mongodb namespace, as reported by `listCollections`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CollectionKind {
+    Collection,
+    View,
+    Timeseries {
+        /// field holding the time of each measurement.
+        time_field: String,
+        /// field describing the series, if any.
+        meta_field: Option<String>,
+    },
+}
+
+/// Information about the scanned collection.
+#[derive(Debug, Clone)]
+pub(crate) struct CollectionInfo {
+    pub(crate) kind: CollectionKind,
+    /// the `$jsonSchema` of the validator of the collection, if any.
+    pub(crate) json_schema: Option<Document>,
+}
+
+impl CollectionInfo {
+    /// Views and time-series collections (which are views on their buckets) have no metadata
+    /// to estimate a count from, the driver's `$collStats` based estimate fails on them.
+    pub(crate) fn can_estimate_count(&self) -> bool {
+        self.kind == CollectionKind::Collection
+    }
+
+    pub(crate) fn time_field(&self) -> Option<&str> {
+        match &self.kind {
+            CollectionKind::Timeseries { time_field, .. } => Some(time_field),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn meta_field(&self) -> Option<&str> {
+        match &self.kind {
+            CollectionKind::Timeseries { meta_field, .. } => meta_field.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Runs `listCollections` for a single collection.
+///
+/// The raw command is used instead of `Database::list_collections` as the driver can't
+/// deserialize the specification of time-series collections.
+pub(crate) fn collection_info(
+    db: &Database,
+    name: &str,
+    comment: Option<&str>,
+) -> PolarsResult<CollectionInfo> {
+    let mut command = doc! { "listCollections": 1, "filter": { "name": name } };
+    if let Some(comment) = comment {
+        command.insert("comment", comment);
+    }
+    let res = db.run_command(command, None).map_err(mongo_error)?;
+
+    let spec = res
+        .get_document("cursor")
+        .and_then(|cursor| cursor.get_array("firstBatch"))
+        .ok()
+        .and_then(|batch| batch.first())
+        .and_then(|spec| spec.as_document());
+
+    let spec = match spec {
+        Some(spec) => spec,
+        // the collection doesn't exist (yet), mongodb treats it as an empty collection.
+        None => {
+            return Ok(CollectionInfo {
+                kind: CollectionKind::Collection,
+                json_schema: None,
+            })
+        }
+    };
+
+    let options = spec.get_document("options").cloned().unwrap_or_default();
+
+    let kind = match spec.get_str("type") {
+        Ok("view") => CollectionKind::View,
+        Ok("timeseries") => {
+            let timeseries = options.get_document("timeseries").map_err(|_| {
+                PolarsError::ComputeError(
+                    format!(
+                        "time-series collection '{}' has no timeseries options",
+                        name
+                    )
+                    .into(),
+                )
+            })?;
+            CollectionKind::Timeseries {
+                time_field: timeseries
+                    .get_str("timeField")
+                    .map_err(|_| {
+                        PolarsError::ComputeError(
+                            format!("time-series collection '{}' has no timeField", name).into(),
+                        )
+                    })?
+                    .to_string(),
+                meta_field: timeseries.get_str("metaField").ok().map(String::from),
+            }
+        }
+        _ => CollectionKind::Collection,
+    };
+
+    let json_schema = options
+        .get_document("validator")
+        .and_then(|validator| validator.get_document("$jsonSchema"))
+        .ok()
+        .cloned();
+
+    Ok(CollectionInfo { kind, json_schema })
+}
+
+/// The average size in bytes of the first `sample` documents matching `filter`, or `None`
+/// if none match. Requires mongodb 4.4 for `$bsonSize`.
+pub(crate) fn average_document_size(
+    collection: &Collection<Document>,
+    filter: Option<&Document>,
+    sample: usize,
+    options: AggregateOptions,
+) -> PolarsResult<Option<f64>> {
+    let pipeline = vec![
+        doc! { "$match": filter.cloned().unwrap_or_default() },
+        doc! { "$limit": sample as i64 },
+        doc! { "$group": { "_id": null, "size": { "$avg": { "$bsonSize": "$$ROOT" } } } },
+    ];
+    let mut cursor = collection
+        .aggregate(pipeline, options)
+        .map_err(mongo_error)?;
+    match cursor.next() {
+        Some(doc) => Ok(doc.map_err(mongo_error)?.get_f64("size").ok()),
+        None => Ok(None),
+    }
+}
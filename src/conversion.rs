@@ -1,6 +1,57 @@
+use polars::frame::row::coerce_data_type;
 use polars::prelude::*;
 
-use mongodb::bson::{Bson, Document};
+use crate::predicate::StoredTypes;
+use crate::EpochUnit;
+
+use mongodb::bson::spec::{BinarySubtype, ElementType};
+use mongodb::bson::{Binary, Bson, DateTime, Decimal128, Document, JavaScriptCodeWithScope, Regex};
+
+pub(crate) const MS_PER_DAY: i64 = 86_400_000;
+
+/// The range of the datetimes polars can represent, from the start of the year -262144 to
+/// the end of the year 262143, in milliseconds from the epoch.
+pub(crate) const MIN_DATETIME_MS: i64 = -8_334_632_937_600_000;
+pub(crate) const MAX_DATETIME_MS: i64 = 8_210_298_412_799_999;
+
+/// Converts bson values before they are read, both for schema inference and for the scan,
+/// e.g. to decode binary values of a custom subtype into strings.
+///
+/// The converted value is read like any other value, so its type decides the type of the
+/// column. Values nested in documents and arrays are converted too.
+pub trait BsonConverter: Send + Sync {
+    /// The value to read instead of `value`, or `None` to read `value` as usual. The default
+    /// reads every value as usual.
+    fn convert(&self, _value: &Bson) -> Option<Bson> {
+        None
+    }
+}
+
+impl std::fmt::Debug for dyn BsonConverter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BsonConverter")
+    }
+}
+
+/// Applies `converter` to the values of `doc`, descending into the values it leaves as is.
+pub(crate) fn convert_values(doc: &mut Document, converter: &dyn BsonConverter) {
+    fn convert(value: &mut Bson, converter: &dyn BsonConverter) {
+        match converter.convert(value) {
+            Some(converted) => *value = converted,
+            None => match value {
+                Bson::Document(doc) => convert_values(doc, converter),
+                Bson::Array(arr) => arr.iter_mut().for_each(|v| convert(v, converter)),
+                _ => {}
+            },
+        }
+    }
+    let keys: Vec<String> = doc.keys().cloned().collect();
+    for key in keys {
+        if let Some(value) = doc.get_mut(&key) {
+            convert(value, converter);
+        }
+    }
+}
 
 #[derive(Debug)]
 #[repr(transparent)]
@@ -34,18 +85,21 @@ impl From<&Bson> for Wrap<DataType> {
     fn from(bson: &Bson) -> Self {
         let dt = match bson {
             Bson::Double(_) => DataType::Float64,
+            Bson::Decimal128(_) => DataType::Float64,
             Bson::String(_) => DataType::Utf8,
 
             Bson::Array(arr) => {
                 use polars::frame::row::coerce_data_type;
 
-                let dtypes: Vec<_> = arr
-                    .iter()
-                    .map(|doc| {
-                        let dt: Self = doc.into();
-                        dt.0
-                    })
-                    .collect();
+                // null elements don't tell the type of the list, `[1, null, 3]` is a list of
+                // integers with a null entry. Coercing their type with the one of the other
+                // elements would give a list of strings.
+                let mut dtypes: Vec<DataType> = Vec::new();
+                for dt in arr.iter().map(|v| Wrap::<DataType>::from(v).0) {
+                    if dt != DataType::Null && !dtypes.contains(&dt) {
+                        dtypes.push(dt);
+                    }
+                }
                 let dtype = if dtypes.is_empty() {
                     DataType::Null
                 } else {
@@ -58,10 +112,14 @@ impl From<&Bson> for Wrap<DataType> {
             Bson::Int32(_) => DataType::Int32,
             Bson::Int64(_) => DataType::Int64,
             Bson::Timestamp(_) => DataType::Utf8,
+            Bson::Binary(b) if uuid_to_string(b).is_some() => DataType::Utf8,
+            Bson::Binary(_) => DataType::List(Box::new(DataType::UInt8)),
             Bson::Document(doc) => return doc.into(),
             Bson::DateTime(_) => DataType::Datetime(TimeUnit::Milliseconds, None),
             Bson::ObjectId(_) => DataType::Utf8,
             Bson::Symbol(_) => DataType::Utf8,
+            Bson::JavaScriptCodeWithScope(_) => DataType::Struct(code_with_scope_fields()),
+            Bson::RegularExpression(_) => DataType::Struct(regex_fields()),
             Bson::Undefined => DataType::Unknown,
             _ => DataType::Utf8,
         };
@@ -73,11 +131,13 @@ impl<'a> From<Bson> for Wrap<AnyValue<'a>> {
     fn from(bson: Bson) -> Self {
         let dt = match bson {
             Bson::Double(v) => AnyValue::Float64(v),
+            Bson::Decimal128(v) => AnyValue::Float64(decimal128_to_f64(&v)),
             Bson::String(v) => AnyValue::Utf8Owned(v),
             Bson::Array(arr) => {
                 let vals: Vec<Wrap<AnyValue>> = arr.iter().map(|v| v.into()).collect();
                 // Wrap is transparent, so this is safe
-                let vals = unsafe { std::mem::transmute::<_, Vec<AnyValue>>(vals) };
+                let vals =
+                    unsafe { std::mem::transmute::<Vec<Wrap<AnyValue>>, Vec<AnyValue>>(vals) };
                 let s = Series::new("", vals);
                 AnyValue::List(s)
             }
@@ -85,16 +145,18 @@ impl<'a> From<Bson> for Wrap<AnyValue<'a>> {
             Bson::Null | Bson::Undefined => AnyValue::Null,
             Bson::Int32(v) => AnyValue::Int32(v),
             Bson::Int64(v) => AnyValue::Int64(v),
-            Bson::Timestamp(v) => AnyValue::Utf8Owned(format!("{:#?}", v)),
+            Bson::Timestamp(v) => AnyValue::Utf8Owned(v.to_string()),
             Bson::DateTime(dt) => {
                 AnyValue::Datetime(dt.timestamp_millis(), TimeUnit::Milliseconds, &None)
             }
-            Bson::Binary(b) => {
-                let s = Series::new("", &b.bytes);
-                AnyValue::List(s)
-            }
+            Bson::Binary(b) => match uuid_to_string(&b) {
+                Some(uuid) => AnyValue::Utf8Owned(uuid),
+                None => AnyValue::List(Series::new("", &b.bytes)),
+            },
             Bson::ObjectId(oid) => AnyValue::Utf8Owned(oid.to_string()),
             Bson::Symbol(s) => AnyValue::Utf8Owned(s),
+            Bson::JavaScriptCodeWithScope(v) => code_with_scope_to_any_value(&v),
+            Bson::RegularExpression(v) => regex_to_any_value(&v),
             v => AnyValue::Utf8Owned(format!("{:#?}", v)),
         };
         Wrap(dt)
@@ -105,11 +167,13 @@ impl<'a, 'b> From<&'b Bson> for Wrap<AnyValue<'a>> {
     fn from(bson: &'b Bson) -> Self {
         let dt = match bson {
             Bson::Double(v) => AnyValue::Float64(*v),
+            Bson::Decimal128(v) => AnyValue::Float64(decimal128_to_f64(v)),
             Bson::String(v) => AnyValue::Utf8Owned(v.clone()),
             Bson::Array(arr) => {
                 let vals: Vec<Wrap<AnyValue>> = arr.iter().map(|v| v.into()).collect();
                 // Wrap is transparent, so this is safe
-                let vals = unsafe { std::mem::transmute::<_, Vec<AnyValue>>(vals) };
+                let vals =
+                    unsafe { std::mem::transmute::<Vec<Wrap<AnyValue>>, Vec<AnyValue>>(vals) };
                 let s = Series::new("", vals);
                 AnyValue::List(s)
             }
@@ -117,11 +181,11 @@ impl<'a, 'b> From<&'b Bson> for Wrap<AnyValue<'a>> {
             Bson::Null | Bson::Undefined => AnyValue::Null,
             Bson::Int32(v) => AnyValue::Int32(*v),
             Bson::Int64(v) => AnyValue::Int64(*v),
-            Bson::Timestamp(v) => AnyValue::Utf8Owned(format!("{:#?}", v)),
-            Bson::Binary(b) => {
-                let s = Series::new("", &b.bytes);
-                AnyValue::List(s)
-            }
+            Bson::Timestamp(v) => AnyValue::Utf8Owned(v.to_string()),
+            Bson::Binary(b) => match uuid_to_string(b) {
+                Some(uuid) => AnyValue::Utf8Owned(uuid),
+                None => AnyValue::List(Series::new("", &b.bytes)),
+            },
             Bson::DateTime(dt) => {
                 AnyValue::Datetime(dt.timestamp_millis(), TimeUnit::Milliseconds, &None)
             }
@@ -140,8 +204,852 @@ impl<'a, 'b> From<&'b Bson> for Wrap<AnyValue<'a>> {
             }
             Bson::ObjectId(oid) => AnyValue::Utf8Owned(oid.to_string()),
             Bson::Symbol(s) => AnyValue::Utf8Owned(s.to_string()),
+            Bson::JavaScriptCodeWithScope(v) => code_with_scope_to_any_value(v),
+            Bson::RegularExpression(v) => regex_to_any_value(v),
             v => AnyValue::Utf8Owned(format!("{:#?}", v)),
         };
         Wrap(dt)
     }
 }
+
+/// Code with scope, left by legacy stored functions, is read as a struct of the code and of
+/// its scope as relaxed extended json.
+fn code_with_scope_fields() -> Vec<Field> {
+    vec![
+        Field::new("code", DataType::Utf8),
+        Field::new("scope", DataType::Utf8),
+    ]
+}
+
+fn code_with_scope_to_any_value<'a>(v: &JavaScriptCodeWithScope) -> AnyValue<'a> {
+    let scope = Bson::Document(v.scope.clone()).into_relaxed_extjson();
+    AnyValue::StructOwned(Box::new((
+        vec![
+            AnyValue::Utf8Owned(v.code.clone()),
+            AnyValue::Utf8Owned(scope.to_string()),
+        ],
+        code_with_scope_fields(),
+    )))
+}
+
+/// Regular expressions are read as a struct of their pattern and options. A field that also
+/// holds other values is read as strings, with regexes formatted as `/pattern/options`.
+fn regex_fields() -> Vec<Field> {
+    vec![
+        Field::new("pattern", DataType::Utf8),
+        Field::new("options", DataType::Utf8),
+    ]
+}
+
+fn regex_to_any_value<'a>(v: &Regex) -> AnyValue<'a> {
+    AnyValue::StructOwned(Box::new((
+        vec![
+            AnyValue::Utf8Owned(v.pattern.clone()),
+            AnyValue::Utf8Owned(v.options.clone()),
+        ],
+        regex_fields(),
+    )))
+}
+
+/// The fields declared by the `properties` of a `$jsonSchema` object, or `None` if the type
+/// of one of them can't be told.
+pub(crate) fn json_schema_fields(schema: &Document, decimal_as_string: bool) -> Option<Vec<Field>> {
+    let properties = schema.get_document("properties").ok()?;
+    if properties.is_empty() {
+        return None;
+    }
+    properties
+        .iter()
+        .map(|(name, property)| {
+            let dtype = json_schema_dtype(property.as_document()?, decimal_as_string)?;
+            Some(Field::new(name, dtype))
+        })
+        .collect()
+}
+
+/// The bson types declared by the `properties` of a `$jsonSchema` object, for the types that
+/// are read as strings. Decimals are read as strings with `decimal_as_string`.
+pub(crate) fn json_schema_types(schema: &Document) -> StoredTypes {
+    let mut stored = StoredTypes::default();
+    for (name, property) in schema.get_document("properties").into_iter().flatten() {
+        let types: Vec<&str> = match property
+            .as_document()
+            .and_then(|p| p.get("bsonType").or_else(|| p.get("type")))
+        {
+            Some(Bson::String(t)) => vec![t.as_str()],
+            Some(Bson::Array(types)) => types.iter().filter_map(|t| t.as_str()).collect(),
+            _ => continue,
+        };
+        let types: Vec<ElementType> = types
+            .into_iter()
+            .filter_map(|t| match t {
+                "string" => Some(ElementType::String),
+                "objectId" => Some(ElementType::ObjectId),
+                "symbol" => Some(ElementType::Symbol),
+                "timestamp" => Some(ElementType::Timestamp),
+                "decimal" => Some(ElementType::Decimal128),
+                "javascript" => Some(ElementType::JavaScriptCode),
+                _ => None,
+            })
+            .collect();
+        if !types.is_empty() {
+            stored.insert(name.clone(), types);
+        }
+    }
+    stored
+}
+
+/// The type of a field declared by its `bsonType` or `type`. Nullable fields declare `null`
+/// next to their type, which is skipped as every column is nullable.
+fn json_schema_dtype(schema: &Document, decimal_as_string: bool) -> Option<DataType> {
+    use polars::frame::row::coerce_data_type;
+
+    let types: Vec<&str> = match schema.get("bsonType").or_else(|| schema.get("type"))? {
+        Bson::String(t) => vec![t.as_str()],
+        Bson::Array(types) => types.iter().filter_map(|t| t.as_str()).collect(),
+        _ => return None,
+    };
+    let dtypes = types
+        .into_iter()
+        .filter(|t| *t != "null")
+        .map(|t| {
+            let dtype = match t {
+                "double" | "number" => DataType::Float64,
+                "decimal" if decimal_as_string => DataType::Utf8,
+                "decimal" => DataType::Float64,
+                "int" => DataType::Int32,
+                "long" | "integer" => DataType::Int64,
+                "bool" | "boolean" => DataType::Boolean,
+                "string" | "objectId" | "symbol" | "timestamp" | "javascript" => DataType::Utf8,
+                "date" => DataType::Datetime(TimeUnit::Milliseconds, None),
+                "binData" => DataType::List(Box::new(DataType::UInt8)),
+                "regex" => DataType::Struct(regex_fields()),
+                "object" => DataType::Struct(json_schema_fields(schema, decimal_as_string)?),
+                // tuples declare `items` as an array of schemas, they have no single type.
+                "array" => DataType::List(Box::new(json_schema_dtype(
+                    schema.get_document("items").ok()?,
+                    decimal_as_string,
+                )?)),
+                _ => return None,
+            };
+            Some(dtype)
+        })
+        .collect::<Option<Vec<_>>>()?;
+    match dtypes.is_empty() {
+        true => None,
+        false => Some(coerce_data_type(&dtypes)),
+    }
+}
+
+/// Whether values inferred as `observed` can be read into a column of type `hinted`.
+pub(crate) fn dtype_compatible(observed: &DataType, hinted: &DataType) -> bool {
+    use DataType::*;
+    match (observed, hinted) {
+        (Null | Unknown, _) | (_, Utf8 | Categorical(_)) => true,
+        (Boolean, _) => hinted.is_numeric() || *hinted == Boolean,
+        (Datetime(_, _), Datetime(_, _) | Date) => true,
+        (_, Datetime(_, _) | Date) => observed.is_numeric(),
+        (List(_), List(_)) | (Struct(_), Struct(_)) => true,
+        _ => (observed.is_numeric() && hinted.is_numeric()) || observed == hinted,
+    }
+}
+
+/// The struct holding every field of the structs in `dtypes`, in the order they are first
+/// seen. Returns `None` unless every type is a struct.
+pub(crate) fn merge_struct_dtypes(dtypes: &[DataType]) -> Option<DataType> {
+    let mut merged: PlIndexMap<&str, Vec<DataType>> = PlIndexMap::default();
+    for dtype in dtypes {
+        match dtype {
+            DataType::Struct(fields) => {
+                for field in fields {
+                    let seen = merged.entry(field.name().as_str()).or_default();
+                    if !seen.contains(field.data_type()) && *field.data_type() != DataType::Null {
+                        seen.push(field.data_type().clone());
+                    }
+                }
+            }
+            _ => return None,
+        }
+    }
+    let fields = merged
+        .into_iter()
+        .map(|(name, seen)| match seen.is_empty() {
+            true => Field::new(name, DataType::Null),
+            false => Field::new(name, coerce_data_type(&seen)),
+        })
+        .collect();
+    Some(DataType::Struct(fields))
+}
+
+/// Reads `doc` as a struct of `fields`, fields missing from `doc` are null and fields that
+/// aren't in `fields` are left out.
+pub(crate) fn document_to_struct<'a>(doc: &Document, fields: &[Field]) -> AnyValue<'a> {
+    let vals = fields
+        .iter()
+        .map(|field| match doc.get(field.name()) {
+            Some(value) => Wrap::<AnyValue>::from(value).0,
+            None => AnyValue::Null,
+        })
+        .collect();
+    AnyValue::StructOwned(Box::new((vals, fields.to_vec())))
+}
+
+/// Reads an integer timestamp since the unix epoch as a bson datetime.
+pub(crate) fn epoch_to_datetime(value: &Bson, unit: EpochUnit) -> Option<DateTime> {
+    let v = match value {
+        Bson::Int32(v) => *v as i64,
+        Bson::Int64(v) => *v,
+        Bson::Double(v) if v.fract() == 0.0 => *v as i64,
+        _ => return None,
+    };
+    let ms = match unit {
+        EpochUnit::Seconds => v.checked_mul(1_000)?,
+        EpochUnit::Milliseconds => v,
+        // rounded down, so that a time before the epoch stays before its millisecond.
+        EpochUnit::Microseconds => v.div_euclid(1_000),
+        EpochUnit::Nanoseconds => v.div_euclid(1_000_000),
+    };
+    Some(DateTime::from_millis(ms))
+}
+
+/// Reads an integer flag as a boolean, zero is `false` and any other number `true`.
+pub(crate) fn int_to_bool(value: &Bson) -> Option<bool> {
+    match value {
+        Bson::Int32(v) => Some(*v != 0),
+        Bson::Int64(v) => Some(*v != 0),
+        Bson::Double(v) => Some(*v != 0.0),
+        _ => None,
+    }
+}
+
+/// Formats a decimal128 as described by the bson decimal128 specification.
+pub(crate) fn decimal128_to_string(d: &Decimal128) -> String {
+    const EXPONENT_BIAS: i32 = 6176;
+    const MAX_COEFFICIENT: u128 = 9_999_999_999_999_999_999_999_999_999_999_999;
+
+    let bits = u128::from_le_bytes(d.bytes());
+    let negative = bits >> 127 == 1;
+    let combination = (bits >> 122) & 0x1f;
+
+    let (exponent, coefficient) = if combination >> 3 == 0b11 {
+        match combination {
+            0x1e if negative => return "-Infinity".into(),
+            0x1e => return "Infinity".into(),
+            0x1f => return "NaN".into(),
+            // the coefficient doesn't fit in 113 bits, which is non canonical and read as zero.
+            _ => ((bits >> 111) & 0x3fff, 0),
+        }
+    } else {
+        ((bits >> 113) & 0x3fff, bits & ((1 << 113) - 1))
+    };
+    let exponent = exponent as i32 - EXPONENT_BIAS;
+    let coefficient = if coefficient > MAX_COEFFICIENT {
+        0
+    } else {
+        coefficient
+    };
+
+    let digits = coefficient.to_string();
+    let adjusted_exponent = exponent + digits.len() as i32 - 1;
+    let sign = if negative { "-" } else { "" };
+
+    if exponent <= 0 && adjusted_exponent >= -6 {
+        let point = digits.len() as i32 + exponent;
+        if exponent == 0 {
+            format!("{}{}", sign, digits)
+        } else if point > 0 {
+            let (int, frac) = digits.split_at(point as usize);
+            format!("{}{}.{}", sign, int, frac)
+        } else {
+            format!("{}0.{}{}", sign, "0".repeat(-point as usize), digits)
+        }
+    } else {
+        let (first, rest) = digits.split_at(1);
+        let point = if rest.is_empty() { "" } else { "." };
+        format!("{}{}{}{}E{:+}", sign, first, point, rest, adjusted_exponent)
+    }
+}
+
+/// The closest `f64` to a decimal128.
+pub(crate) fn decimal128_to_f64(d: &Decimal128) -> f64 {
+    decimal128_to_string(d).parse().unwrap_or(f64::NAN)
+}
+
+/// Replaces all decimal128 values in `doc`, including nested ones, with their exact string.
+pub(crate) fn decimals_to_strings(doc: &mut Document) {
+    fn convert(value: &mut Bson) {
+        match value {
+            Bson::Decimal128(d) => *value = Bson::String(decimal128_to_string(d)),
+            Bson::Document(doc) => decimals_to_strings(doc),
+            Bson::Array(arr) => arr.iter_mut().for_each(convert),
+            _ => {}
+        }
+    }
+    let keys: Vec<String> = doc.keys().cloned().collect();
+    for key in keys {
+        if let Some(value) = doc.get_mut(&key) {
+            convert(value);
+        }
+    }
+}
+
+/// The canonical `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx` form of a binary of the UUID
+/// subtype `4`. The legacy subtype `3` isn't converted, its byte order depends on the driver
+/// that wrote it.
+pub(crate) fn uuid_to_string(binary: &Binary) -> Option<String> {
+    if binary.subtype != BinarySubtype::Uuid || binary.bytes.len() != 16 {
+        return None;
+    }
+    let hex: String = binary.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+/// The UUID binary of a canonical UUID string, the inverse of `uuid_to_string`.
+pub(crate) fn uuid_from_str(uuid: &str) -> Option<Binary> {
+    if !uuid.is_ascii() {
+        return None;
+    }
+    let groups: Vec<&str> = uuid.split('-').collect();
+    if groups.iter().map(|g| g.len()).collect::<Vec<_>>() != [8, 4, 4, 4, 12] {
+        return None;
+    }
+    let hex = groups.concat();
+    let bytes = (0..16)
+        .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(Binary {
+        subtype: BinarySubtype::Uuid,
+        bytes,
+    })
+}
+
+/// Replaces the subtype of all UUID binaries in `doc`, including nested ones, with the generic
+/// subtype, so that they are read as bytes instead of as UUID strings.
+pub(crate) fn uuids_to_bytes(doc: &mut Document) {
+    fn convert(value: &mut Bson) {
+        match value {
+            Bson::Binary(b) if b.subtype == BinarySubtype::Uuid => {
+                b.subtype = BinarySubtype::Generic
+            }
+            Bson::Document(doc) => uuids_to_bytes(doc),
+            Bson::Array(arr) => arr.iter_mut().for_each(convert),
+            _ => {}
+        }
+    }
+    let keys: Vec<String> = doc.keys().cloned().collect();
+    for key in keys {
+        if let Some(value) = doc.get_mut(&key) {
+            convert(value);
+        }
+    }
+}
+
+/// Converts the rows of `df` into documents, e.g. to insert them with the mongodb driver.
+///
+/// This inverts the mapping of the scan: structs become embedded documents, lists become
+/// arrays, datetimes and dates become bson datetimes and categoricals become strings.
+/// Decimals read as `Float64` or as strings stay doubles and strings.
+/// Unsigned integers that don't fit in an `Int64` are an error. If `skip_nulls` is set,
+/// null values are left out of the documents instead of being written as `null`.
+pub fn dataframe_to_documents(df: &DataFrame, skip_nulls: bool) -> PolarsResult<Vec<Document>> {
+    let columns = df
+        .get_columns()
+        .iter()
+        .map(|s| match s.dtype() {
+            DataType::Categorical(_) => s.cast(&DataType::Utf8),
+            _ => Ok(s.clone()),
+        })
+        .collect::<PolarsResult<Vec<_>>>()?;
+
+    (0..df.height())
+        .map(|idx| {
+            let mut doc = Document::new();
+            for s in &columns {
+                let value = any_value_to_bson(&s.get(idx))?;
+                if !(skip_nulls && value == Bson::Null) {
+                    doc.insert(s.name(), value);
+                }
+            }
+            Ok(doc)
+        })
+        .collect()
+}
+
+fn any_value_to_bson(av: &AnyValue) -> PolarsResult<Bson> {
+    let v = match av {
+        AnyValue::Null => Bson::Null,
+        AnyValue::Boolean(v) => Bson::Boolean(*v),
+        AnyValue::Utf8(v) => Bson::String(v.to_string()),
+        AnyValue::Utf8Owned(v) => Bson::String(v.clone()),
+        AnyValue::Int8(v) => Bson::Int32(*v as i32),
+        AnyValue::Int16(v) => Bson::Int32(*v as i32),
+        AnyValue::Int32(v) => Bson::Int32(*v),
+        AnyValue::Int64(v) => Bson::Int64(*v),
+        AnyValue::UInt8(v) => Bson::Int32(*v as i32),
+        AnyValue::UInt16(v) => Bson::Int32(*v as i32),
+        AnyValue::UInt32(v) => Bson::Int64(*v as i64),
+        AnyValue::UInt64(v) => Bson::Int64(i64::try_from(*v).map_err(|_| {
+            PolarsError::ComputeError(format!("{} doesn't fit in a bson Int64", v).into())
+        })?),
+        AnyValue::Float32(v) => Bson::Double(*v as f64),
+        AnyValue::Float64(v) => Bson::Double(*v),
+        AnyValue::Datetime(v, tu, _) => {
+            let ms = match tu {
+                TimeUnit::Nanoseconds => v / 1_000_000,
+                TimeUnit::Microseconds => v / 1_000,
+                TimeUnit::Milliseconds => *v,
+            };
+            Bson::DateTime(DateTime::from_millis(ms))
+        }
+        AnyValue::Date(days) => Bson::DateTime(DateTime::from_millis(*days as i64 * MS_PER_DAY)),
+        AnyValue::List(s) => Bson::Array(
+            (0..s.len())
+                .map(|idx| any_value_to_bson(&s.get(idx)))
+                .collect::<PolarsResult<_>>()?,
+        ),
+        AnyValue::Struct(vals, fields) => struct_to_bson(vals, fields)?,
+        AnyValue::StructOwned(payload) => struct_to_bson(&payload.0, &payload.1)?,
+        av => {
+            return Err(PolarsError::ComputeError(
+                format!("{} can't be converted to bson", av).into(),
+            ))
+        }
+    };
+    Ok(v)
+}
+
+fn struct_to_bson(vals: &[AnyValue], fields: &[Field]) -> PolarsResult<Bson> {
+    let doc = fields
+        .iter()
+        .zip(vals)
+        .map(|(field, av)| Ok((field.name().to_string(), any_value_to_bson(av)?)))
+        .collect::<PolarsResult<Document>>()?;
+    Ok(Bson::Document(doc))
+}
+
+/// Replaces numbers stored in their extended json form, e.g. `{"$numberLong": "42"}` left by
+/// loading a `mongoexport` dump as is, with the number they wrap. Nested values are replaced
+/// as well.
+pub(crate) fn unwrap_extended_json_numbers(doc: &mut Document) {
+    fn number(doc: &Document) -> Option<Bson> {
+        if doc.len() != 1 {
+            return None;
+        }
+        let (key, value) = doc.iter().next()?;
+        let value = value.as_str()?;
+        let n = match key.as_str() {
+            "$numberInt" => Bson::Int32(value.parse().ok()?),
+            "$numberLong" => Bson::Int64(value.parse().ok()?),
+            "$numberDouble" => Bson::Double(value.parse().ok()?),
+            _ => return None,
+        };
+        Some(n)
+    }
+    fn convert(value: &mut Bson) {
+        match value {
+            Bson::Document(doc) => match number(doc) {
+                Some(n) => *value = n,
+                None => unwrap_extended_json_numbers(doc),
+            },
+            Bson::Array(arr) => arr.iter_mut().for_each(convert),
+            _ => {}
+        }
+    }
+    let keys: Vec<String> = doc.keys().cloned().collect();
+    for key in keys {
+        if let Some(value) = doc.get_mut(&key) {
+            convert(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    /// The decimal128 `coefficient * 10^exponent`.
+    fn decimal(negative: bool, coefficient: u128, exponent: i32) -> Decimal128 {
+        let bits = ((negative as u128) << 127) | (((exponent + 6176) as u128) << 113) | coefficient;
+        Decimal128::from_bytes(bits.to_le_bytes())
+    }
+
+    #[test]
+    fn decimals_are_formatted_exactly() {
+        assert_eq!(decimal128_to_string(&decimal(false, 1, -1)), "0.1");
+        assert_eq!(decimal128_to_string(&decimal(true, 12345, -2)), "-123.45");
+        assert_eq!(decimal128_to_string(&decimal(false, 42, 0)), "42");
+        assert_eq!(decimal128_to_string(&decimal(false, 1, -7)), "1E-7");
+        assert_eq!(decimal128_to_string(&decimal(false, 15, 3)), "1.5E+4");
+        assert_eq!(decimal128_to_f64(&decimal(false, 25, -1)), 2.5);
+    }
+
+    #[test]
+    fn decimals_to_strings_converts_nested_values() {
+        let mut doc = doc! {
+            "a": decimal(false, 1, -1),
+            "b": { "c": decimal(false, 2, -1) },
+            "d": [decimal(false, 3, -1), 4],
+            "e": 0.5,
+        };
+        decimals_to_strings(&mut doc);
+        assert_eq!(
+            doc,
+            doc! { "a": "0.1", "b": { "c": "0.2" }, "d": ["0.3", 4], "e": 0.5 }
+        );
+    }
+
+    #[test]
+    fn epochs_overflowing_a_datetime_are_not_converted() {
+        let dt = epoch_to_datetime(&Bson::Int64(1_000), EpochUnit::Seconds);
+        assert_eq!(dt, Some(DateTime::from_millis(1_000_000)));
+        assert_eq!(
+            epoch_to_datetime(&Bson::Int64(i64::MAX), EpochUnit::Seconds),
+            None
+        );
+        assert_eq!(
+            epoch_to_datetime(&Bson::Double(1.5), EpochUnit::Seconds),
+            None
+        );
+        assert_eq!(
+            epoch_to_datetime(&Bson::Int64(-1_500_000), EpochUnit::Microseconds),
+            Some(DateTime::from_millis(-1_500))
+        );
+    }
+
+    #[test]
+    fn epochs_before_1970_are_rounded_down() {
+        assert_eq!(
+            epoch_to_datetime(&Bson::Int64(-1), EpochUnit::Microseconds),
+            Some(DateTime::from_millis(-1))
+        );
+        assert_eq!(
+            epoch_to_datetime(&Bson::Int64(-1_500), EpochUnit::Microseconds),
+            Some(DateTime::from_millis(-2))
+        );
+        assert_eq!(
+            epoch_to_datetime(&Bson::Int64(-1_000_001), EpochUnit::Nanoseconds),
+            Some(DateTime::from_millis(-2))
+        );
+        assert_eq!(
+            epoch_to_datetime(&Bson::Int64(1_999_999), EpochUnit::Nanoseconds),
+            Some(DateTime::from_millis(1))
+        );
+    }
+
+    #[test]
+    fn uuids_round_trip_through_their_canonical_string() {
+        let bytes: Vec<u8> = (0..16).map(|b| b * 17).collect();
+        let binary = Binary {
+            subtype: BinarySubtype::Uuid,
+            bytes: bytes.clone(),
+        };
+        let uuid = "00112233-4455-6677-8899-aabbccddeeff";
+        assert_eq!(uuid_to_string(&binary).as_deref(), Some(uuid));
+        assert_eq!(uuid_from_str(uuid), Some(binary));
+        // upper case parses to the same UUID, which is read in lower case.
+        assert_eq!(
+            uuid_from_str(&uuid.to_uppercase()).and_then(|b| uuid_to_string(&b)),
+            Some(uuid.to_string())
+        );
+
+        // the legacy subtype isn't converted.
+        let legacy = Binary {
+            subtype: BinarySubtype::UuidOld,
+            bytes,
+        };
+        assert_eq!(uuid_to_string(&legacy), None);
+        for invalid in [
+            "00112233445566778899aabbccddeeff",
+            "0011223-34455-6677-8899-aabbccddeeff",
+            "00112233-4455-6677-8899-aabbccddeefg",
+            "00112233-4455-6677-8899-aabbccddeé",
+        ] {
+            assert_eq!(uuid_from_str(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn dataframe_to_documents_round_trips_read_documents() {
+        let docs = vec![
+            doc! {
+                "a": 1,
+                "b": "x",
+                "c": DateTime::from_millis(1_000),
+                "d": { "e": 1.5, "f": true },
+                "g": [1_i64, 2_i64],
+            },
+            doc! {
+                "a": 2,
+                "c": DateTime::from_millis(-1_000),
+                "d": { "e": 2.5, "f": false },
+                "g": [3_i64],
+            },
+        ];
+        let schema: Schema = docs[0]
+            .iter()
+            .map(|(name, value)| Field::new(name, Wrap::<DataType>::from(value).0))
+            .collect();
+        let opts = crate::buffer::ParseOptions {
+            invalid_values: crate::InvalidValuePolicy::Null,
+            out_of_range_datetimes: crate::OutOfRangePolicy::Null,
+            struct_null_policy: crate::StructNullPolicy::PerField,
+            empty_string_as_null: false,
+        };
+        let mut buffers = crate::buffer::init_buffers(&schema, docs.len()).unwrap();
+        for doc in &docs {
+            for (name, buffer) in buffers.iter_mut() {
+                match doc.get(name) {
+                    Some(value) => buffer.add(value, &opts).unwrap(),
+                    None => buffer.add_null(),
+                }
+            }
+        }
+        let df = DataFrame::new(
+            buffers
+                .into_values()
+                .map(|buffer| buffer.into_series())
+                .collect::<PolarsResult<Vec<_>>>()
+                .unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(dataframe_to_documents(&df, true).unwrap(), docs);
+        // without skipping nulls the missing string is written as null.
+        let written = dataframe_to_documents(&df, false).unwrap();
+        assert_eq!(written[1].get("b"), Some(&Bson::Null));
+    }
+
+    #[test]
+    fn meta_structs_hold_every_field_seen() {
+        let dtypes = [
+            Wrap::<DataType>::from(&doc! { "sensor": 1, "site": "a" }).0,
+            Wrap::<DataType>::from(&doc! { "sensor": 2_i64, "kind": "t" }).0,
+        ];
+        let merged = merge_struct_dtypes(&dtypes).unwrap();
+        let fields = vec![
+            Field::new("sensor", DataType::Int64),
+            Field::new("site", DataType::Utf8),
+            Field::new("kind", DataType::Utf8),
+        ];
+        assert_eq!(merged, DataType::Struct(fields.clone()));
+        assert_eq!(merge_struct_dtypes(&[merged, DataType::Utf8]), None);
+
+        // documents are read in the order of the fields, with the missing ones null.
+        let av = document_to_struct(&doc! { "kind": "t", "sensor": 2_i64 }, &fields);
+        let vals = match av {
+            AnyValue::StructOwned(payload) => payload.0,
+            av => panic!("{:?} isn't a struct", av),
+        };
+        // owned strings don't compare equal in polars 0.24.
+        assert_eq!(format!("{:?}", vals), r#"[Int64(2), Null, Utf8Owned("t")]"#);
+    }
+
+    #[test]
+    fn extended_json_numbers_are_unwrapped() {
+        let mut doc = doc! {
+            "int": { "$numberInt": "42" },
+            "long": { "$numberLong": "9007199254740993" },
+            "double": { "$numberDouble": "1.5" },
+            "nested": { "a": [{ "$numberInt": "1" }, { "b": { "$numberLong": "2" } }] },
+            // not numbers in their extended json form, left as they are.
+            "invalid": { "$numberInt": "x" },
+            "extra": { "$numberInt": "1", "b": 2 },
+            "other": { "$date": "2020-01-01" },
+        };
+        unwrap_extended_json_numbers(&mut doc);
+        assert_eq!(
+            doc,
+            doc! {
+                "int": 42,
+                "long": 9007199254740993_i64,
+                "double": 1.5,
+                "nested": { "a": [1, { "b": 2_i64 }] },
+                "invalid": { "$numberInt": "x" },
+                "extra": { "$numberInt": "1", "b": 2 },
+                "other": { "$date": "2020-01-01" },
+            }
+        );
+    }
+
+    #[test]
+    fn regexes_are_read_as_structs_of_pattern_and_options() {
+        let pattern = Regex {
+            pattern: "^ab+c".into(),
+            options: "i".into(),
+        };
+        let regex = Bson::RegularExpression(pattern.clone());
+        assert_eq!(
+            Wrap::<DataType>::from(&regex).0,
+            DataType::Struct(regex_fields())
+        );
+
+        let s = Series::new("a", [Wrap::<AnyValue>::from(&regex).0, AnyValue::Null]);
+        let fields: Vec<Vec<_>> = s
+            .struct_()
+            .unwrap()
+            .fields()
+            .iter()
+            .map(|s| {
+                s.utf8()
+                    .unwrap()
+                    .into_iter()
+                    .map(|v| v.map(String::from))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                vec![Some("^ab+c".to_string()), None],
+                vec![Some("i".to_string()), None]
+            ]
+        );
+        // in a column of strings, a regex is formatted as a literal.
+        assert_eq!(pattern.to_string(), "/^ab+c/i");
+    }
+
+    #[test]
+    fn code_with_scope_is_read_as_a_struct_of_code_and_scope() {
+        let code = Bson::JavaScriptCodeWithScope(JavaScriptCodeWithScope {
+            code: "function() { return x; }".into(),
+            scope: doc! { "x": 1_i64 },
+        });
+        assert_eq!(
+            Wrap::<DataType>::from(&code).0,
+            DataType::Struct(code_with_scope_fields())
+        );
+
+        let s = Series::new("a", [Wrap::<AnyValue>::from(&code).0]);
+        let fields: Vec<_> = s
+            .struct_()
+            .unwrap()
+            .fields()
+            .iter()
+            .map(|s| s.utf8().unwrap().get(0).map(String::from))
+            .collect();
+        // the scope is formatted as relaxed extended json.
+        assert_eq!(
+            fields,
+            [
+                Some("function() { return x; }".to_string()),
+                Some(r#"{"x":1}"#.to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn converter_decodes_a_custom_binary_subtype() {
+        /// Decodes the utf-8 bytes of the user defined subtype `0x80`.
+        struct Utf8Binary;
+        impl BsonConverter for Utf8Binary {
+            fn convert(&self, value: &Bson) -> Option<Bson> {
+                match value {
+                    Bson::Binary(Binary {
+                        subtype: BinarySubtype::UserDefined(0x80),
+                        bytes,
+                    }) => Some(Bson::String(String::from_utf8(bytes.clone()).ok()?)),
+                    _ => None,
+                }
+            }
+        }
+        let custom = |text: &str| Binary {
+            subtype: BinarySubtype::UserDefined(0x80),
+            bytes: text.as_bytes().to_vec(),
+        };
+        let generic = Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: vec![1, 2],
+        };
+        let mut doc = doc! {
+            "a": custom("x"),
+            "b": { "c": [custom("y"), 1] },
+            "d": generic.clone(),
+        };
+        convert_values(&mut doc, &Utf8Binary);
+        assert_eq!(doc, doc! { "a": "x", "b": { "c": ["y", 1] }, "d": generic });
+    }
+
+    #[test]
+    fn integer_flags_are_read_as_booleans() {
+        assert_eq!(int_to_bool(&Bson::Int32(0)), Some(false));
+        assert_eq!(int_to_bool(&Bson::Int32(1)), Some(true));
+        assert_eq!(int_to_bool(&Bson::Int64(-2)), Some(true));
+        assert_eq!(int_to_bool(&Bson::Double(0.0)), Some(false));
+        assert_eq!(int_to_bool(&Bson::Double(0.5)), Some(true));
+        // other values are left as they are.
+        assert_eq!(int_to_bool(&Bson::Boolean(false)), None);
+        assert_eq!(int_to_bool(&Bson::String("1".into())), None);
+        assert_eq!(int_to_bool(&Bson::Null), None);
+    }
+
+    #[test]
+    fn json_schema_properties_are_read_as_fields() {
+        let schema = doc! {
+            "bsonType": "object",
+            "properties": {
+                "name": { "bsonType": ["string", "null"] },
+                "count": { "bsonType": "int" },
+                "tags": { "type": "array", "items": { "bsonType": "string" } },
+                "address": {
+                    "bsonType": "object",
+                    "properties": {
+                        "city": { "bsonType": "string" },
+                        "location": {
+                            "bsonType": "object",
+                            "properties": { "lat": { "bsonType": "double" } },
+                        },
+                    },
+                },
+                "price": { "bsonType": "decimal" },
+            },
+        };
+        let location = DataType::Struct(vec![Field::new("lat", DataType::Float64)]);
+        let address = DataType::Struct(vec![
+            Field::new("city", DataType::Utf8),
+            Field::new("location", location),
+        ]);
+        let fields = |price| {
+            vec![
+                Field::new("name", DataType::Utf8),
+                Field::new("count", DataType::Int32),
+                Field::new("tags", DataType::List(Box::new(DataType::Utf8))),
+                Field::new("address", address.clone()),
+                Field::new("price", price),
+            ]
+        };
+        assert_eq!(
+            json_schema_fields(&schema, false),
+            Some(fields(DataType::Float64))
+        );
+        assert_eq!(
+            json_schema_fields(&schema, true),
+            Some(fields(DataType::Utf8))
+        );
+
+        // a type that can't be read, or a property only declaring `null`, has no type, and
+        // neither have the fields around it.
+        for property in [
+            doc! { "bsonType": "minKey" },
+            doc! { "bsonType": ["null"] },
+            doc! { "enum": [1, 2] },
+        ] {
+            assert_eq!(json_schema_dtype(&property, false), None, "{}", property);
+            let nested = doc! {
+                "properties": {
+                    "a": { "bsonType": "int" },
+                    "b": { "bsonType": "object", "properties": { "c": property } },
+                },
+            };
+            assert_eq!(json_schema_fields(&nested, false), None);
+        }
+        assert_eq!(json_schema_fields(&doc! { "properties": {} }, false), None);
+    }
+}
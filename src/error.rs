@@ -0,0 +1,65 @@
+use mongodb::error::{Error, ErrorKind};
+use polars::prelude::*;
+
+/// Server error codes of a document exceeding the 16MB bson size limit.
+const BSON_OBJECT_TOO_LARGE: i32 = 10334;
+const BSON_OBJECT_TOO_LARGE_AGGREGATION: i32 = 4568;
+
+/// Converts a driver error, with a hint on how to avoid the common ones.
+pub(crate) fn mongo_error(err: Error) -> PolarsError {
+    match err.kind.as_ref() {
+        ErrorKind::Command(e)
+            if e.code == BSON_OBJECT_TOO_LARGE
+                || e.code == BSON_OBJECT_TOO_LARGE_AGGREGATION
+                || e.code_name == "BSONObjectTooLarge" =>
+        {
+            PolarsError::ComputeError(
+                format!(
+                    "a document exceeds the 16MB bson size limit: {}. Select fewer columns \
+                     to project the document down, or let the pipeline spill to disk with \
+                     `allowDiskUse`",
+                    e.message
+                )
+                .into(),
+            )
+        }
+        _ => PolarsError::ComputeError(format!("{:#?}", err).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::{doc, from_document};
+    use mongodb::error::CommandError;
+
+    fn command_error(code: i32, code_name: &str) -> Error {
+        let err: CommandError = from_document(doc! {
+            "code": code,
+            "codeName": code_name,
+            "errmsg": "BSONObj size: 17825792 (0x1100000) is invalid",
+        })
+        .unwrap();
+        Error::from(ErrorKind::Command(err))
+    }
+
+    #[test]
+    fn oversized_documents_suggest_a_projection() {
+        for err in [
+            command_error(BSON_OBJECT_TOO_LARGE, "BSONObjectTooLarge"),
+            command_error(BSON_OBJECT_TOO_LARGE_AGGREGATION, "Location4568"),
+        ] {
+            let msg = mongo_error(err).to_string();
+            assert!(msg.contains("16MB bson size limit: BSONObj size: 17825792"));
+            assert!(msg.contains("Select fewer columns"));
+            assert!(msg.contains("allowDiskUse"));
+        }
+    }
+
+    #[test]
+    fn other_errors_are_dumped() {
+        let msg = mongo_error(command_error(13, "Unauthorized")).to_string();
+        assert!(msg.contains("Unauthorized"));
+        assert!(!msg.contains("allowDiskUse"));
+    }
+}
@@ -0,0 +1,139 @@
+use mongodb::bson::{doc, to_bson, Bson, Document};
+use mongodb::options::FindOptions;
+use mongodb::sync::Database;
+use polars::prelude::*;
+
+use crate::error::mongo_error;
+
+/// Stages that read the documents themselves, a query using any of them isn't covered.
+const FETCHING_STAGES: [&str; 2] = ["FETCH", "COLLSCAN"];
+
+/// Explains the find that the scan is going to run and errors if its winning plan fetches
+/// documents, i.e. if it isn't answered from an index alone.
+pub(crate) fn assert_covered(
+    db: &Database,
+    collection: &str,
+    filter: Option<&Document>,
+    options: &FindOptions,
+) -> PolarsResult<()> {
+    let plan = winning_plan(db, collection, filter, options)?;
+
+    match find_stage(&plan, &FETCHING_STAGES) {
+        Some(stage) => Err(PolarsError::ComputeError(
+            format!(
+                "query on '{}' isn't covered by an index, its plan has a {} stage. \
+                 Only project indexed fields, and exclude `_id` unless it is indexed",
+                collection, stage
+            )
+            .into(),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Explains the find that the scan is going to run and tells whether its winning plan reads
+/// the whole collection, i.e. if no index narrows down the filter.
+pub(crate) fn is_collection_scan(
+    db: &Database,
+    collection: &str,
+    filter: Option<&Document>,
+    options: &FindOptions,
+) -> PolarsResult<bool> {
+    let plan = winning_plan(db, collection, filter, options)?;
+    Ok(find_stage(&plan, &["COLLSCAN"]).is_some())
+}
+
+/// The winning plan of the find, from an `explain` in `queryPlanner` verbosity, which plans
+/// the query without running it.
+fn winning_plan(
+    db: &Database,
+    collection: &str,
+    filter: Option<&Document>,
+    options: &FindOptions,
+) -> PolarsResult<Document> {
+    let find = find_command(collection, filter, options)?;
+    let mut explain = doc! { "explain": find, "verbosity": "queryPlanner" };
+    if let Some(comment) = &options.comment {
+        explain.insert("comment", comment.clone());
+    }
+
+    let res = db.run_command(explain, None).map_err(mongo_error)?;
+
+    res.get_document("queryPlanner")
+        .and_then(|planner| planner.get_document("winningPlan"))
+        .cloned()
+        .map_err(|_| {
+            PolarsError::ComputeError(
+                format!("explain of '{}' returned no winning plan", collection).into(),
+            )
+        })
+}
+
+/// The raw `find` command equivalent to `Collection::find` with `options`.
+pub(crate) fn find_command(
+    collection: &str,
+    filter: Option<&Document>,
+    options: &FindOptions,
+) -> PolarsResult<Document> {
+    let mut find = doc! { "find": collection };
+    if let Some(filter) = filter {
+        find.insert("filter", filter.clone());
+    }
+    if let Some(projection) = &options.projection {
+        find.insert("projection", projection.clone());
+    }
+    if let Some(sort) = &options.sort {
+        find.insert("sort", sort.clone());
+    }
+    if let Some(hint) = &options.hint {
+        let hint =
+            to_bson(hint).map_err(|err| PolarsError::ComputeError(format!("{}", err).into()))?;
+        find.insert("hint", hint);
+    }
+    if let Some(collation) = &options.collation {
+        let collation = to_bson(collation)
+            .map_err(|err| PolarsError::ComputeError(format!("{}", err).into()))?;
+        find.insert("collation", collation);
+    }
+    if let Some(let_vars) = &options.let_vars {
+        find.insert("let", let_vars.clone());
+    }
+    if let Some(skip) = options.skip {
+        find.insert("skip", skip as i64);
+    }
+    if let Some(limit) = options.limit {
+        find.insert("limit", limit);
+    }
+    if let Some(batch_size) = options.batch_size {
+        find.insert("batchSize", batch_size as i64);
+    }
+    if let Some(comment) = &options.comment {
+        find.insert("comment", comment.clone());
+    }
+    if let Some(no_cursor_timeout) = options.no_cursor_timeout {
+        find.insert("noCursorTimeout", no_cursor_timeout);
+    }
+    if let Some(return_key) = options.return_key {
+        find.insert("returnKey", return_key);
+    }
+    if let Some(show_record_id) = options.show_record_id {
+        find.insert("showRecordId", show_record_id);
+    }
+    Ok(find)
+}
+
+/// Finds one of `stages` anywhere in the plan tree. Walking every nested value handles
+/// `inputStage(s)`, sharded plans and the `queryPlan` of slot based plans alike.
+fn find_stage<'a>(plan: &'a Document, stages: &[&str]) -> Option<&'a str> {
+    fn walk<'a>(value: &'a Bson, stages: &[&str]) -> Option<&'a str> {
+        match value {
+            Bson::Document(doc) => find_stage(doc, stages),
+            Bson::Array(arr) => arr.iter().find_map(|value| walk(value, stages)),
+            _ => None,
+        }
+    }
+    match plan.get_str("stage") {
+        Ok(stage) if stages.contains(&stage) => Some(stage),
+        _ => plan.values().find_map(|value| walk(value, stages)),
+    }
+}
@@ -0,0 +1,184 @@
+use mongodb::bson::{Bson, Document};
+use mongodb::options::{Hint, ListIndexesOptions};
+use mongodb::sync::Collection;
+use mongodb::IndexModel;
+use polars::prelude::*;
+
+use crate::error::mongo_error;
+
+/// Picks the index whose leading keys match the most fields of `filter`, as the hint of the
+/// scan. Ties go to the index with fewer keys, which is smaller to read. Returns `None` if no
+/// index starts with a filtered field.
+///
+/// Sparse and partial indexes are left out, a hint forces mongodb to use them even if they
+/// don't hold every matching document. Text and geospatial indexes can't answer a plain
+/// filter and are left out too, as are hashed indexes, which can't answer a range.
+pub(crate) fn choose_hint(
+    collection: &Collection<Document>,
+    filter: &Document,
+    comment: &str,
+) -> PolarsResult<Option<Hint>> {
+    let mut fields = Vec::new();
+    filter_fields(filter, &mut fields);
+    if fields.is_empty() {
+        return Ok(None);
+    }
+    let options = ListIndexesOptions::builder()
+        .comment(Some(Bson::String(comment.to_string())))
+        .build();
+    let indexes = collection
+        .list_indexes(options)
+        .map_err(mongo_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(mongo_error)?;
+    Ok(best_index(indexes, &fields).map(Hint::Keys))
+}
+
+/// The keys of the index of `indexes` to hint for a filter on `fields`, see `choose_hint`.
+fn best_index(indexes: Vec<IndexModel>, fields: &[String]) -> Option<Document> {
+    let mut best: Option<(usize, Document)> = None;
+    for index in indexes {
+        let partial = index.options.as_ref().is_some_and(|options| {
+            options.sparse == Some(true) || options.partial_filter_expression.is_some()
+        });
+        let special = index
+            .keys
+            .values()
+            .any(|kind| matches!(kind, Bson::String(_)));
+        if partial || special {
+            continue;
+        }
+        let matched = index
+            .keys
+            .keys()
+            .take_while(|key| fields.contains(key))
+            .count();
+        let better = match &best {
+            None => matched > 0,
+            Some((best_matched, best_keys)) => {
+                matched > *best_matched
+                    || (matched == *best_matched && index.keys.len() < best_keys.len())
+            }
+        };
+        if better {
+            best = Some((matched, index.keys));
+        }
+    }
+    best.map(|(_, keys)| keys)
+}
+
+/// Operators matching every value but a few, an index on their field barely narrows them down.
+const NEGATIONS: [&str; 3] = ["$ne", "$nin", "$not"];
+
+/// The fields filtered on by `filter`, including those of its `$and` clauses. The clauses of
+/// `$or` and `$nor` aren't required to match, an index on their fields doesn't narrow down
+/// the filter, and neither does one on a field only filtered by negations.
+fn filter_fields(filter: &Document, fields: &mut Vec<String>) {
+    for (key, value) in filter {
+        match (key.as_str(), value) {
+            ("$and", Bson::Array(clauses)) => {
+                for clause in clauses {
+                    if let Bson::Document(clause) = clause {
+                        filter_fields(clause, fields);
+                    }
+                }
+            }
+            (key, _) if key.starts_with('$') => {}
+            (_, Bson::Document(condition))
+                if !condition.is_empty()
+                    && condition.keys().all(|op| NEGATIONS.contains(&op.as_str())) => {}
+            (key, _) => fields.push(key.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+    use mongodb::options::IndexOptions;
+
+    fn index(keys: Document) -> IndexModel {
+        IndexModel::builder().keys(keys).build()
+    }
+
+    #[test]
+    fn filter_fields_skip_or_clauses() {
+        let filter = doc! {
+            "a": 1,
+            "$and": [{ "b": { "$gt": 1 } }, { "$or": [{ "c": 1 }, { "d": 1 }] }],
+            "$nor": [{ "e": 1 }],
+        };
+        let mut fields = Vec::new();
+        filter_fields(&filter, &mut fields);
+        assert_eq!(fields, ["a", "b"]);
+    }
+
+    #[test]
+    fn filter_fields_skip_negations() {
+        let filter = doc! {
+            "a": { "$ne": 1 },
+            "b": { "$nin": [1, 2] },
+            "c": { "$not": { "$gt": 1 } },
+            "d": { "$gt": 1, "$ne": 5 },
+            "$and": [{ "e": { "$ne": 1 } }, { "f": 1 }],
+        };
+        let mut fields = Vec::new();
+        filter_fields(&filter, &mut fields);
+        assert_eq!(fields, ["d", "f"]);
+        // an index on a negated field isn't hinted.
+        let indexes = vec![index(doc! { "a": 1 }), index(doc! { "d": 1 })];
+        assert_eq!(best_index(indexes, &fields), Some(doc! { "d": 1 }));
+    }
+
+    #[test]
+    fn best_index_matches_the_most_leading_fields() {
+        let fields = vec!["a".to_string(), "b".to_string()];
+        let indexes = || {
+            vec![
+                index(doc! { "_id": 1 }),
+                index(doc! { "a": 1, "c": 1, "b": 1 }),
+                index(doc! { "a": 1 }),
+                index(doc! { "a": 1, "b": -1, "c": 1 }),
+                index(doc! { "a": 1, "b": 1 }),
+            ]
+        };
+        // ties go to the index with fewer keys.
+        assert_eq!(
+            best_index(indexes(), &fields),
+            Some(doc! { "a": 1, "b": 1 })
+        );
+        assert_eq!(best_index(indexes(), &["c".to_string()]), None);
+    }
+
+    #[test]
+    fn best_index_leaves_out_partial_and_special_indexes() {
+        let fields = vec!["a".to_string()];
+        let sparse = IndexModel::builder()
+            .keys(doc! { "a": 1 })
+            .options(IndexOptions::builder().sparse(true).build())
+            .build();
+        let partial = IndexModel::builder()
+            .keys(doc! { "a": 1 })
+            .options(
+                IndexOptions::builder()
+                    .partial_filter_expression(doc! { "a": { "$exists": true } })
+                    .build(),
+            )
+            .build();
+        let text = index(doc! { "a": "text" });
+        let hashed = index(doc! { "a": "hashed" });
+        assert_eq!(
+            best_index(vec![sparse, partial, text, hashed], &fields),
+            None
+        );
+        let compound_hashed = index(doc! { "a": 1, "b": "hashed" });
+        assert_eq!(
+            best_index(
+                vec![compound_hashed, index(doc! { "a": 1, "c": 1 })],
+                &fields
+            ),
+            Some(doc! { "a": 1, "c": 1 })
+        );
+    }
+}
@@ -0,0 +1,385 @@
+use mongodb::bson::{doc, oid::ObjectId, spec::ElementType, Bson, DateTime, Document};
+use mongodb::options::{FindOneOptions, FindOptions};
+use mongodb::sync::{Client, Collection};
+use polars::prelude::*;
+
+use crate::error::mongo_error;
+use crate::predicate::combine;
+use crate::ResumePosition;
+
+/// Returns the value of `path`, `_id` or one of its sub-fields, starting each partition after
+/// the first, so that every partition holds `rows_per_partition` documents. Each bound is
+/// read with its own `skip` over the index of `path`, so only the bounds are sent back.
+///
+/// Less than `n_partitions - 1` bounds are returned if the collection has fewer documents
+/// than expected, e.g. after an estimated count.
+pub(crate) fn id_bounds(
+    collection: &Collection<Document>,
+    path: &str,
+    filter: Option<&Document>,
+    comment: Option<String>,
+    rows_per_partition: usize,
+    n_partitions: usize,
+) -> PolarsResult<Vec<Bson>> {
+    // more partitions than documents would otherwise start every partition at `0`.
+    let rows_per_partition = rows_per_partition.max(1);
+    let mut bounds = Vec::with_capacity(n_partitions.saturating_sub(1));
+    for idx in 1..n_partitions {
+        let options = FindOneOptions::builder()
+            .projection(doc! { path: 1 })
+            .sort(doc! { path: 1 })
+            .skip((idx * rows_per_partition) as u64)
+            .comment(comment.clone())
+            .build();
+        let doc = collection
+            .find_one(filter.cloned(), options)
+            .map_err(mongo_error)?;
+        match doc {
+            Some(doc) => {
+                if let Some(id) = get_path(&doc, path) {
+                    bounds.push(id.clone());
+                }
+            }
+            None => break,
+        }
+    }
+    Ok(bounds)
+}
+
+/// The value at a dotted `path` of `doc`, e.g. `_id.ts`.
+pub(crate) fn get_path<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+    let mut parts = path.split('.');
+    let mut value = doc.get(parts.next()?)?;
+    for part in parts {
+        value = value.as_document()?.get(part)?;
+    }
+    Some(value)
+}
+
+/// The filter of the partition `idx`, reading the values of `path` from its bound up to the
+/// next one. The first partition starts at `MinKey` and the last one ends at `MaxKey`, which
+/// compare below and above values of every type, so that no document is left out at the ends.
+pub(crate) fn id_range_filter(
+    filter: Option<&Document>,
+    path: &str,
+    bounds: &[Bson],
+    idx: usize,
+) -> Option<Document> {
+    let lower = match idx {
+        0 => Bson::MinKey,
+        idx => bounds[idx - 1].clone(),
+    };
+    let range = match bounds.get(idx) {
+        Some(upper) => doc! { "$gte": lower, "$lt": upper.clone() },
+        None => doc! { "$gte": lower, "$lte": Bson::MaxKey },
+    };
+    combine(filter.cloned(), Some(doc! { path: range }), "$and")
+}
+
+/// Matches the documents with an `_id` after `id`. Object ids are read as hex strings, so a
+/// string that is a valid object id also matches the object ids after it.
+pub(crate) fn after_id_filter(id: &Bson) -> Document {
+    match id {
+        Bson::String(hex) => match ObjectId::parse_str(hex) {
+            Ok(oid) => doc! { "$or": [
+                { "_id": { "$gt": oid } },
+                { "_id": { "$gt": hex } },
+            ] },
+            Err(_) => doc! { "_id": { "$gt": hex } },
+        },
+        id => doc! { "_id": { "$gt": id.clone() } },
+    }
+}
+
+/// Leaves out the `_id` ranges read by the partitions of an earlier scan, from the bound each
+/// started at up to the last `_id` it read.
+pub(crate) fn unread_filter(positions: &[ResumePosition]) -> Option<Document> {
+    if positions.is_empty() {
+        return None;
+    }
+    let read: Vec<Document> = positions
+        .iter()
+        .map(|position| {
+            doc! { &position.path: { "$gte": position.start.clone(), "$lte": position.last_read.clone() } }
+        })
+        .collect();
+    Some(doc! { "$nor": read })
+}
+
+/// How the documents of a scan are split between its partitions.
+pub(crate) enum Partitions {
+    /// consecutive `skip`/`limit` windows of the query.
+    Windows,
+    /// ranges of `_id`, or of the sub-field at the path, starting at each bound, see
+    /// `id_bounds`.
+    IdRanges(String, Vec<Bson>),
+    /// the chunk ranges of one shard per partition, see `shard_filters`.
+    Shards(Vec<Document>),
+    /// equal intervals of a time window, see `time_range_filters`.
+    TimeRanges(Vec<Document>),
+}
+
+/// Splits the time window `[start, end)` into `n_partitions` intervals of equal length, one
+/// range filter on `field` each. Time-series collections store their measurements in buckets
+/// ordered by time, so each range only reads the buckets of its interval.
+pub(crate) fn time_range_filters(
+    field: &str,
+    start: DateTime,
+    end: DateTime,
+    n_partitions: usize,
+) -> Vec<Document> {
+    // the length of the window can exceed an `i64`, e.g. from `DateTime::MIN` to `MAX`, the
+    // bounds are computed as `i128` and stay within the window.
+    let (start, end) = (
+        start.timestamp_millis() as i128,
+        end.timestamp_millis() as i128,
+    );
+    let step = ((end - start) / n_partitions as i128).max(1);
+    let mut filters = Vec::with_capacity(n_partitions);
+    let mut lower = start;
+    while lower < end && filters.len() < n_partitions {
+        // the last interval takes the remainder of the division.
+        let upper = match filters.len() + 1 == n_partitions {
+            true => end,
+            false => (lower + step).min(end),
+        };
+        filters.push(doc! { field: {
+            "$gte": DateTime::from_millis(lower as i64),
+            "$lt": DateTime::from_millis(upper as i64),
+        } });
+        lower = upper;
+    }
+    filters
+}
+
+/// One filter per shard of a sharded collection, matching the shard key ranges of the
+/// chunks on that shard, so that every partition reads from a single shard.
+///
+/// Returns `None` if the collection isn't sharded, or if its shard key is hashed or compound,
+/// as the ranges of such keys can't be expressed as a filter on the documents, and if the
+/// bounds of its chunks are of different types, see `chunk_filters`.
+pub(crate) fn shard_filters(
+    client: &Client,
+    db: &str,
+    collection: &str,
+    comment: &str,
+) -> PolarsResult<Option<Vec<Document>>> {
+    let ns = format!("{}.{}", db, collection);
+    let config = client.database("config");
+    let options = FindOneOptions::builder()
+        .comment(comment.to_string())
+        .build();
+    let spec = config
+        .collection::<Document>("collections")
+        .find_one(doc! { "_id": &ns, "dropped": { "$ne": true } }, options)
+        .map_err(mongo_error)?;
+    let spec = match spec {
+        Some(spec) => spec,
+        None => return Ok(None),
+    };
+    let field = match spec.get_document("key") {
+        Ok(key) if key.len() == 1 => match key.iter().next() {
+            Some((_, Bson::String(_))) | None => return Ok(None),
+            Some((field, _)) => field.clone(),
+        },
+        _ => return Ok(None),
+    };
+
+    // chunks refer to their collection by uuid since mongodb 5.0, and by namespace before.
+    let chunks_filter = match spec.get("uuid") {
+        Some(uuid) => doc! { "$or": [{ "uuid": uuid.clone() }, { "ns": &ns }] },
+        None => doc! { "ns": &ns },
+    };
+    let options = FindOptions::builder()
+        .sort(doc! { "min": 1 })
+        .comment(comment.to_string())
+        .build();
+    let chunks = config
+        .collection::<Document>("chunks")
+        .find(chunks_filter, options)
+        .map_err(mongo_error)?;
+
+    let mut bounds = Vec::new();
+    for chunk in chunks {
+        let chunk = chunk.map_err(mongo_error)?;
+        let bound = |name: &str| {
+            chunk
+                .get_document(name)
+                .ok()
+                .and_then(|bound| bound.get(&field))
+                .cloned()
+        };
+        if let (Ok(shard), Some(min), Some(max)) =
+            (chunk.get_str("shard"), bound("min"), bound("max"))
+        {
+            bounds.push((shard.to_string(), min, max));
+        }
+    }
+    Ok(chunk_filters(&field, bounds))
+}
+
+/// One filter per shard from the `(shard, min, max)` bounds of the chunks, in the order of
+/// their `min`.
+///
+/// Mongodb only compares values of the same type, `{$gte: 5, $lt: 10}` doesn't match `null`
+/// or a string. The first chunk, starting at `MinKey`, holds the missing and null keys and
+/// every key of another type than the bounds, so it matches whatever the other chunks
+/// don't. Returns `None` if the bounds are of different types, no range filter then
+/// matches the keys between them.
+fn chunk_filters(field: &str, bounds: Vec<(String, Bson, Bson)>) -> Option<Vec<Document>> {
+    // numbers of any type compare with each other.
+    let bracket = |bound: &Bson| match bound.element_type() {
+        ElementType::Int32 | ElementType::Int64 | ElementType::Decimal128 => ElementType::Double,
+        element_type => element_type,
+    };
+    let types: Vec<ElementType> = bounds
+        .iter()
+        .flat_map(|(_, min, max)| [min, max])
+        .filter(|bound| !matches!(bound, Bson::MinKey | Bson::MaxKey))
+        .map(bracket)
+        .collect();
+    if types.windows(2).any(|pair| pair[0] != pair[1]) {
+        return None;
+    }
+
+    let mut ranges: PlIndexMap<String, Vec<Bson>> = PlIndexMap::default();
+    for (shard, min, max) in bounds {
+        let range = match (min, max) {
+            (Bson::MinKey, Bson::MaxKey) => doc! {},
+            (Bson::MinKey, max) => doc! { field: { "$not": { "$gte": max } } },
+            (min, Bson::MaxKey) => doc! { field: { "$gte": min } },
+            (min, max) => doc! { field: { "$gte": min, "$lt": max } },
+        };
+        ranges.entry(shard).or_default().push(Bson::Document(range));
+    }
+    if ranges.is_empty() {
+        return None;
+    }
+    Some(
+        ranges
+            .into_values()
+            .map(|ranges| doc! { "$or": ranges })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_ranges_cover_every_id_once() {
+        let bounds = [Bson::Int32(10), Bson::Int32(20)];
+        let filter = doc! { "a": 1 };
+        assert_eq!(
+            id_range_filter(Some(&filter), "_id", &bounds, 0),
+            Some(doc! { "$and": [
+                { "a": 1 },
+                { "_id": { "$gte": Bson::MinKey, "$lt": 10 } },
+            ] })
+        );
+        assert_eq!(
+            id_range_filter(None, "_id", &bounds, 1),
+            Some(doc! { "_id": { "$gte": 10, "$lt": 20 } })
+        );
+        assert_eq!(
+            id_range_filter(None, "_id.ts", &bounds, 2),
+            Some(doc! { "_id.ts": { "$gte": 20, "$lte": Bson::MaxKey } })
+        );
+    }
+
+    #[test]
+    fn chunk_filters_leave_no_key_out() {
+        let chunk = |shard: &str, min: Bson, max: Bson| (shard.to_string(), min, max);
+        let bounds = vec![
+            chunk("a", Bson::MinKey, Bson::Int32(10)),
+            chunk("b", Bson::Int32(10), Bson::Int64(20)),
+            chunk("a", Bson::Int64(20), Bson::MaxKey),
+        ];
+        // null, missing and non numeric keys are all read by the first chunk.
+        assert_eq!(
+            chunk_filters("k", bounds),
+            Some(vec![
+                doc! { "$or": [
+                    { "k": { "$not": { "$gte": 10 } } },
+                    { "k": { "$gte": 20_i64 } },
+                ] },
+                doc! { "$or": [{ "k": { "$gte": 10, "$lt": 20_i64 } }] },
+            ])
+        );
+
+        let single = vec![chunk("a", Bson::MinKey, Bson::MaxKey)];
+        assert_eq!(chunk_filters("k", single), Some(vec![doc! { "$or": [{}] }]));
+
+        // a range from a number to a string matches neither.
+        let mixed = vec![
+            chunk("a", Bson::MinKey, Bson::Int32(10)),
+            chunk("b", Bson::Int32(10), Bson::String("m".into())),
+            chunk("a", Bson::String("m".into()), Bson::MaxKey),
+        ];
+        assert_eq!(chunk_filters("k", mixed), None);
+        assert_eq!(chunk_filters("k", vec![]), None);
+    }
+
+    #[test]
+    fn get_path_reads_sub_fields() {
+        let doc = doc! { "_id": { "ts": 1, "n": 2 } };
+        assert_eq!(get_path(&doc, "_id.ts"), Some(&Bson::Int32(1)));
+        assert_eq!(get_path(&doc, "_id.missing"), None);
+        assert_eq!(get_path(&doc, "_id"), doc.get("_id"));
+    }
+
+    #[test]
+    fn time_ranges_split_the_window() {
+        let range = |lower: i64, upper: i64| {
+            doc! { "ts": {
+                "$gte": DateTime::from_millis(lower),
+                "$lt": DateTime::from_millis(upper),
+            } }
+        };
+        let (start, end) = (DateTime::from_millis(0), DateTime::from_millis(10));
+        // the last range takes the remainder.
+        assert_eq!(
+            time_range_filters("ts", start, end, 3),
+            vec![range(0, 3), range(3, 6), range(6, 10)]
+        );
+        // ranges are never shorter than a millisecond.
+        assert_eq!(
+            time_range_filters("ts", start, DateTime::from_millis(2), 4),
+            vec![range(0, 1), range(1, 2)]
+        );
+        assert!(time_range_filters("ts", end, start, 2).is_empty());
+        // the whole range of datetimes is longer than an `i64`.
+        assert_eq!(
+            time_range_filters("ts", DateTime::MIN, DateTime::MAX, 2),
+            vec![range(i64::MIN, -1), range(-1, i64::MAX)]
+        );
+    }
+
+    #[test]
+    fn unread_filter_skips_the_ranges_read_before() {
+        assert_eq!(unread_filter(&[]), None);
+
+        // of the partitions [MinKey, 10), [10, 20) and [20, MaxKey], the first and the last
+        // were read before the scan was interrupted.
+        let positions = vec![
+            ResumePosition {
+                path: "_id".into(),
+                start: Bson::MinKey,
+                last_read: Bson::Int32(9),
+            },
+            ResumePosition {
+                path: "_id".into(),
+                start: Bson::Int32(20),
+                last_read: Bson::Int32(31),
+            },
+        ];
+        assert_eq!(
+            unread_filter(&positions),
+            Some(doc! { "$nor": [
+                { "_id": { "$gte": Bson::MinKey, "$lte": 9 } },
+                { "_id": { "$gte": 20, "$lte": 31 } },
+            ] })
+        );
+    }
+}
@@ -0,0 +1,443 @@
+use mongodb::bson::spec::ElementType;
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime, Document};
+use polars::prelude::*;
+
+use crate::conversion::{uuid_from_str, uuid_to_string};
+use crate::projection::needs_escaping;
+
+/// The bson types stored under the columns of the schema, from the sampled documents or the
+/// validator of the collection. Nulls aren't recorded.
+pub(crate) type StoredTypes = PlHashMap<String, Vec<ElementType>>;
+
+/// A polars predicate split into the part mongodb can evaluate and the part it can't.
+#[derive(Debug, Default)]
+pub(crate) struct Translated {
+    /// filter document sent to mongodb.
+    pub(crate) filter: Option<Document>,
+    /// what is left of the predicate, evaluated in memory after the scan.
+    pub(crate) residual: Option<Expr>,
+}
+
+impl Translated {
+    fn pushed(filter: Document) -> Self {
+        Translated {
+            filter: Some(filter),
+            residual: None,
+        }
+    }
+
+    fn in_memory(expr: &Expr) -> Self {
+        Translated {
+            filter: None,
+            residual: Some(expr.clone()),
+        }
+    }
+}
+
+/// Translates a polars predicate into a mongodb filter document.
+///
+/// `AND` combinators push every translatable branch and keep the others as a residual.
+/// `OR` combinators are only pushed if every branch translates, as pushing half of an `OR`
+/// would drop rows that the other half matches.
+///
+/// `schema` is the schema of the collection, it is used to convert literals to the bson type
+/// of the column they are compared with. Comparisons on columns missing from it aren't pushed.
+/// `stored` tells the bson types behind string columns, see `string_comparison`.
+pub(crate) fn translate(expr: &Expr, schema: &Schema, stored: &StoredTypes) -> Translated {
+    match expr {
+        Expr::Alias(e, _) => translate(e, schema, stored),
+        Expr::BinaryExpr { left, op, right } => match op {
+            Operator::And => {
+                let left = translate(left, schema, stored);
+                let right = translate(right, schema, stored);
+                Translated {
+                    filter: combine(left.filter, right.filter, "$and"),
+                    residual: match (left.residual, right.residual) {
+                        (Some(l), Some(r)) => Some(l.and(r)),
+                        (l, r) => l.or(r),
+                    },
+                }
+            }
+            Operator::Or => {
+                let left = translate(left, schema, stored);
+                let right = translate(right, schema, stored);
+                match (left, right) {
+                    (
+                        Translated {
+                            filter: Some(l),
+                            residual: None,
+                        },
+                        Translated {
+                            filter: Some(r),
+                            residual: None,
+                        },
+                    ) => Translated::pushed(combine(Some(l), Some(r), "$or").unwrap()),
+                    _ => Translated::in_memory(expr),
+                }
+            }
+            _ => comparison(left, *op, right, schema, stored)
+                .map(Translated::pushed)
+                .unwrap_or_else(|| Translated::in_memory(expr)),
+        },
+        _ => Translated::in_memory(expr),
+    }
+}
+
+/// Joins two filters under `op`, flattening nested combinators of the same kind.
+pub(crate) fn combine(
+    left: Option<Document>,
+    right: Option<Document>,
+    op: &str,
+) -> Option<Document> {
+    match (left, right) {
+        (Some(l), Some(r)) => {
+            let mut clauses = vec![];
+            for d in [l, r] {
+                match d.get_array(op) {
+                    Ok(inner) if d.len() == 1 => clauses.extend(inner.iter().cloned()),
+                    _ => clauses.push(Bson::Document(d)),
+                }
+            }
+            Some(doc! { op: clauses })
+        }
+        (l, r) => l.or(r),
+    }
+}
+
+fn comparison(
+    left: &Expr,
+    op: Operator,
+    right: &Expr,
+    schema: &Schema,
+    stored: &StoredTypes,
+) -> Option<Document> {
+    let (name, op, lv) = match (left, right) {
+        (Expr::Column(name), Expr::Literal(lv)) => (name.as_ref(), op, lv),
+        (Expr::Literal(lv), Expr::Column(name)) => (name.as_ref(), flip(op)?, lv),
+        (Expr::Column(left), Expr::Column(right)) => {
+            return field_comparison(left, op, right, schema)
+        }
+        _ => return None,
+    };
+    // a filter on `a.b` matches the nested path, not the literal field.
+    if needs_escaping(name) {
+        return None;
+    }
+    let value = match (schema.get(name)?, lv) {
+        (DataType::Datetime(tu, _), lv) => datetime_literal_to_bson(lv, *tu)?,
+        (DataType::Utf8 | DataType::Categorical(_), LiteralValue::Utf8(v)) => {
+            return string_comparison(name, op, v, stored.get(name).map(|t| t.as_slice()))
+        }
+        (_, lv) => literal_to_bson(lv)?,
+    };
+    let filter = match op {
+        Operator::Eq => doc! { name: value },
+        // polars drops rows where the comparison is null, mongodb `$ne` would keep them.
+        Operator::NotEq => doc! { name: { "$nin": [value, Bson::Null] } },
+        Operator::Lt => doc! { name: { "$lt": value } },
+        Operator::LtEq => doc! { name: { "$lte": value } },
+        Operator::Gt => doc! { name: { "$gt": value } },
+        Operator::GtEq => doc! { name: { "$gte": value } },
+        _ => return None,
+    };
+    Some(filter)
+}
+
+/// Compares a string column with a string. Object ids are read as hex strings and UUID
+/// binaries as canonical UUIDs, a column that holds them (or one of unknown types) is matched
+/// with both the string and the object id or UUID it parses to. Ranges of strings don't order
+/// these, they are only pushed if the column holds strings alone. Other values read as
+/// strings, e.g. timestamps or decimals read with `decimal_as_string`, are compared in memory:
+/// `"0.1"` doesn't match the decimal `0.1` in mongodb.
+fn string_comparison(
+    name: &str,
+    op: Operator,
+    v: &str,
+    stored: Option<&[ElementType]>,
+) -> Option<Document> {
+    let holds = |t: ElementType| stored.is_none_or(|types| types.contains(&t));
+    if let Some(types) = stored {
+        if types.iter().any(|t| {
+            !matches!(
+                t,
+                ElementType::String | ElementType::ObjectId | ElementType::Binary
+            )
+        }) {
+            return None;
+        }
+    }
+    let mut values = vec![Bson::String(v.to_string())];
+    // object ids are read as lower case hex, an upper case one would match in mongodb only.
+    if holds(ElementType::ObjectId) {
+        if let Some(oid) = ObjectId::parse_str(v).ok().filter(|oid| oid.to_hex() == v) {
+            values.push(Bson::ObjectId(oid));
+        }
+    }
+    // only UUIDs are read as strings, other binaries are read as lists.
+    if holds(ElementType::Binary) {
+        // UUIDs are read in lower case, an upper case one would match in mongodb only.
+        if let Some(uuid) = uuid_from_str(v).filter(|b| uuid_to_string(b).as_deref() == Some(v)) {
+            values.push(Bson::Binary(uuid));
+        }
+    }
+    let only_strings =
+        stored.is_some() && !holds(ElementType::ObjectId) && !holds(ElementType::Binary);
+    let filter = match op {
+        Operator::Eq if values.len() == 1 => doc! { name: values.remove(0) },
+        Operator::Eq => doc! { name: { "$in": values } },
+        // polars drops rows where the comparison is null, mongodb `$nin` would keep them.
+        Operator::NotEq => {
+            values.push(Bson::Null);
+            doc! { name: { "$nin": values } }
+        }
+        Operator::Lt if only_strings => doc! { name: { "$lt": v } },
+        Operator::LtEq if only_strings => doc! { name: { "$lte": v } },
+        Operator::Gt if only_strings => doc! { name: { "$gt": v } },
+        Operator::GtEq if only_strings => doc! { name: { "$gte": v } },
+        _ => return None,
+    };
+    Some(filter)
+}
+
+/// Compares two fields of the same document with `$expr`. Aggregation comparisons treat null
+/// as smaller than any value, while polars drops rows where either side is null, so both
+/// fields are also required to be set.
+fn field_comparison(left: &str, op: Operator, right: &str, schema: &Schema) -> Option<Document> {
+    if needs_escaping(left) || needs_escaping(right) {
+        return None;
+    }
+    schema.get(left)?;
+    schema.get(right)?;
+    let op = match op {
+        Operator::Eq => "$eq",
+        Operator::NotEq => "$ne",
+        Operator::Lt => "$lt",
+        Operator::LtEq => "$lte",
+        Operator::Gt => "$gt",
+        Operator::GtEq => "$gte",
+        _ => return None,
+    };
+    Some(doc! {
+        left: { "$ne": null },
+        right: { "$ne": null },
+        "$expr": { op: [format!("${}", left), format!("${}", right)] },
+    })
+}
+
+/// Mirrors a comparison so that the column is on the left hand side.
+fn flip(op: Operator) -> Option<Operator> {
+    let op = match op {
+        Operator::Eq => Operator::Eq,
+        Operator::NotEq => Operator::NotEq,
+        Operator::Lt => Operator::Gt,
+        Operator::LtEq => Operator::GtEq,
+        Operator::Gt => Operator::Lt,
+        Operator::GtEq => Operator::LtEq,
+        _ => return None,
+    };
+    Some(op)
+}
+
+fn literal_to_bson(lv: &LiteralValue) -> Option<Bson> {
+    let v = match lv {
+        LiteralValue::Boolean(v) => Bson::Boolean(*v),
+        LiteralValue::Utf8(v) => Bson::String(v.clone()),
+        LiteralValue::Int8(v) => Bson::Int32(*v as i32),
+        LiteralValue::Int16(v) => Bson::Int32(*v as i32),
+        LiteralValue::Int32(v) => Bson::Int32(*v),
+        LiteralValue::Int64(v) => Bson::Int64(*v),
+        LiteralValue::UInt8(v) => Bson::Int32(*v as i32),
+        LiteralValue::UInt16(v) => Bson::Int32(*v as i32),
+        LiteralValue::UInt32(v) => Bson::Int64(*v as i64),
+        LiteralValue::UInt64(v) => Bson::Int64(i64::try_from(*v).ok()?),
+        LiteralValue::Float32(v) => Bson::Double(*v as f64),
+        LiteralValue::Float64(v) => Bson::Double(*v),
+        LiteralValue::DateTime(dt, _) => {
+            Bson::DateTime(DateTime::from_millis(dt.timestamp_millis()))
+        }
+        _ => return None,
+    };
+    Some(v)
+}
+
+/// Integer literals compared with a datetime column are timestamps in the unit of that column.
+/// Mongodb won't match a date with a number, so these are converted into bson datetimes.
+fn datetime_literal_to_bson(lv: &LiteralValue, tu: TimeUnit) -> Option<Bson> {
+    let v = match lv {
+        LiteralValue::Int32(v) => *v as i64,
+        LiteralValue::Int64(v) => *v,
+        LiteralValue::UInt32(v) => *v as i64,
+        LiteralValue::UInt64(v) => i64::try_from(*v).ok()?,
+        _ => return literal_to_bson(lv),
+    };
+    let ms = match tu {
+        TimeUnit::Nanoseconds => v / 1_000_000,
+        TimeUnit::Microseconds => v / 1_000,
+        TimeUnit::Milliseconds => v,
+    };
+    Some(Bson::DateTime(DateTime::from_millis(ms)))
+}
+
+/// Names of all columns referenced by `expr`.
+pub(crate) fn column_names(expr: &Expr) -> Vec<String> {
+    expr.into_iter()
+        .filter_map(|e| match e {
+            Expr::Column(name) => Some(name.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema() -> Schema {
+        Schema::from(
+            vec![
+                Field::new("_id", DataType::Utf8),
+                Field::new("a", DataType::Int32),
+                Field::new("b", DataType::Int32),
+                Field::new("name", DataType::Utf8),
+            ]
+            .into_iter(),
+        )
+    }
+
+    fn stored(types: &[(&str, &[ElementType])]) -> StoredTypes {
+        types
+            .iter()
+            .map(|(name, types)| (name.to_string(), types.to_vec()))
+            .collect()
+    }
+
+    fn pushed(expr: Expr, stored: &StoredTypes) -> Option<Document> {
+        let translated = translate(&expr, &schema(), stored);
+        assert_eq!(translated.residual, None);
+        translated.filter
+    }
+
+    #[test]
+    fn nested_and_or_are_pushed_flattened() {
+        let expr = col("a")
+            .gt(lit(1))
+            .and(col("a").lt(lit(10)).and(col("b").eq(lit(2))))
+            .or(col("b").gt_eq(lit(5)).or(col("b").lt_eq(lit(0))));
+        assert_eq!(
+            pushed(expr, &StoredTypes::default()),
+            Some(doc! { "$or": [
+                { "$and": [
+                    { "a": { "$gt": 1 } },
+                    { "a": { "$lt": 10 } },
+                    { "b": 2 },
+                ] },
+                { "b": { "$gte": 5 } },
+                { "b": { "$lte": 0 } },
+            ] })
+        );
+    }
+
+    #[test]
+    fn partial_and_keeps_the_rest_in_memory() {
+        let expr = col("a").eq(lit(1)).and(col("b").is_null());
+        let translated = translate(&expr, &schema(), &StoredTypes::default());
+        assert_eq!(translated.filter, Some(doc! { "a": 1 }));
+        assert_eq!(translated.residual, Some(col("b").is_null()));
+    }
+
+    #[test]
+    fn or_with_an_untranslatable_branch_stays_in_memory() {
+        let expr = col("a").eq(lit(1)).or(col("b").is_null());
+        let translated = translate(&expr, &schema(), &StoredTypes::default());
+        assert_eq!(translated.filter, None);
+        assert_eq!(translated.residual, Some(expr));
+
+        // columns missing from the schema aren't pushed either.
+        let expr = col("a").eq(lit(1)).or(col("missing").eq(lit(1)));
+        assert_eq!(
+            translate(&expr, &schema(), &StoredTypes::default()).filter,
+            None
+        );
+    }
+
+    #[test]
+    fn not_eq_drops_nulls_like_polars() {
+        assert_eq!(
+            pushed(col("a").neq(lit(1)), &StoredTypes::default()),
+            Some(doc! { "a": { "$nin": [1, null] } })
+        );
+    }
+
+    #[test]
+    fn literals_on_the_left_are_flipped() {
+        assert_eq!(
+            pushed(lit(1).lt(col("a")), &StoredTypes::default()),
+            Some(doc! { "a": { "$gt": 1 } })
+        );
+    }
+
+    #[test]
+    fn field_comparisons_use_expr() {
+        assert_eq!(
+            pushed(col("a").lt(col("b")), &StoredTypes::default()),
+            Some(doc! {
+                "a": { "$ne": null },
+                "b": { "$ne": null },
+                "$expr": { "$lt": ["$a", "$b"] },
+            })
+        );
+    }
+
+    #[test]
+    fn strings_match_object_ids_they_parse_to() {
+        let hex = "62f0a1b2c3d4e5f6a7b8c9d0";
+        let oid = ObjectId::parse_str(hex).unwrap();
+        let stored = stored(&[("_id", &[ElementType::ObjectId])]);
+        assert_eq!(
+            pushed(col("_id").eq(lit(hex)), &stored),
+            Some(doc! { "_id": { "$in": [hex, oid] } })
+        );
+        assert_eq!(
+            pushed(col("_id").neq(lit(hex)), &stored),
+            Some(doc! { "_id": { "$nin": [hex, oid, null] } })
+        );
+        // ranges of strings don't order object ids.
+        let expr = col("_id").gt(lit(hex));
+        assert_eq!(translate(&expr, &schema(), &stored).filter, None);
+        // upper case hex isn't how object ids are read.
+        assert_eq!(
+            pushed(col("_id").eq(lit(hex.to_uppercase())), &stored),
+            Some(doc! { "_id": hex.to_uppercase() })
+        );
+    }
+
+    #[test]
+    fn strings_match_uuids_they_parse_to() {
+        let uuid = "0e0b8a2c-5f1d-4e7a-9c3b-1a2b3c4d5e6f";
+        let binary = uuid_from_str(uuid).unwrap();
+        let stored = stored(&[("name", &[ElementType::Binary, ElementType::String])]);
+        assert_eq!(
+            pushed(col("name").eq(lit(uuid)), &stored),
+            Some(doc! { "name": { "$in": [uuid, binary] } })
+        );
+    }
+
+    #[test]
+    fn string_ranges_are_pushed_on_string_columns_only() {
+        let strings = stored(&[("name", &[ElementType::String])]);
+        assert_eq!(
+            pushed(col("name").gt_eq(lit("m")), &strings),
+            Some(doc! { "name": { "$gte": "m" } })
+        );
+        // the stored types aren't known, the column may hold object ids.
+        let expr = col("name").gt_eq(lit("m"));
+        assert_eq!(
+            translate(&expr, &schema(), &StoredTypes::default()).filter,
+            None
+        );
+        // values read as strings but stored otherwise are compared in memory.
+        let decimals = stored(&[("name", &[ElementType::Decimal128])]);
+        let expr = col("name").eq(lit("0.1"));
+        assert_eq!(translate(&expr, &schema(), &decimals).filter, None);
+    }
+}
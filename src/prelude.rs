@@ -1 +1,11 @@
-pub use crate::{MongoLazyReader, MongoScan, MongoScanOptions};
+#[cfg(feature = "aws-auth")]
+pub use crate::auth::aws_credential;
+pub use crate::conversion::{dataframe_to_documents, BsonConverter};
+pub use crate::write::{write_mongo_collection, MongoWriteOptions, WriteError, WriteSummary};
+pub use crate::{
+    BenchmarkHook, CountMode, DocumentTransform, EpochUnit, InvalidValuePolicy, MongoLazyReader,
+    MongoScan, MongoScanOptions, OutOfRangePolicy, PlannedQuery, ResumePosition, ScanPhase,
+    StructNullPolicy, RAW_COLUMN,
+};
+pub use mongodb::bson::{doc, Bson, DateTime, Document, Timestamp};
+pub use mongodb::options::{Acknowledgment, Credential, Hint, WriteConcern};
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+
+use mongodb::bson::{doc, Bson, Document};
+use polars::prelude::*;
+
+/// Key of the record id added to the documents by `showRecordId`. It isn't a field, so it
+/// is neither projected nor escaped.
+pub(crate) const RECORD_ID: &str = "$recordId";
+
+/// Top level field names containing a `.` or starting with a `$` can't be used as is in a
+/// projection or a filter, as mongodb interprets them as paths or operators.
+pub(crate) fn needs_escaping(name: &str) -> bool {
+    name.contains('.') || name.starts_with('$')
+}
+
+/// Replaces `.` and a leading `$` with their full width unicode equivalents.
+pub(crate) fn escape_field_name(name: &str) -> String {
+    let name = name.replace('.', "\u{FF0E}");
+    match name.strip_prefix('$') {
+        Some(rest) => format!("\u{FF04}{}", rest),
+        None => name,
+    }
+}
+
+/// Builds an inclusion projection for all fields in `schema`.
+///
+/// If `escape` is set, fields that need escaping are fetched with `$getField` under their
+/// escaped name, so that `a.b` refers to the literal field and not to `b` nested in `a`.
+pub(crate) fn build_projection(schema: &Schema, escape: bool) -> Document {
+    schema
+        .iter_names()
+        .map(|name| {
+            if escape && needs_escaping(name) {
+                (
+                    escape_field_name(name),
+                    Bson::Document(doc! { "$getField": { "field": { "$literal": name } } }),
+                )
+            } else {
+                (name.clone(), Bson::Int64(1))
+            }
+        })
+        .collect()
+}
+
+/// Replaces the inclusion of the struct column `name` in `projection` with the inclusion of
+/// its `fields`, so that the other fields of the subdocument aren't fetched.
+pub(crate) fn prune_struct_fields(projection: &mut Document, name: &str, fields: &[Field]) {
+    if needs_escaping(name) || fields.is_empty() || projection.remove(name).is_none() {
+        return;
+    }
+    for field in fields {
+        let path = format!("{}.{}", name, field.name());
+        projection.insert(path.clone(), Bson::Int64(1));
+        if let DataType::Struct(nested) = field.data_type() {
+            prune_struct_fields(projection, &path, nested);
+        }
+    }
+}
+
+/// Adds a `$slice` of the array columns in `slices` that are read, keeping the first `n`
+/// elements of each array, or the last `-n` ones if `n` is negative. `$slice` works in both
+/// inclusion and exclusion projections, and on its own leaves the other fields as they are.
+pub(crate) fn slice_arrays(
+    projection: Option<Document>,
+    schema: &Schema,
+    slices: &HashMap<String, i64>,
+) -> Option<Document> {
+    let mut sliced = Vec::new();
+    for (name, n) in slices {
+        if schema.get(name).is_some() && !needs_escaping(name) {
+            sliced.push((name.clone(), Bson::Document(doc! { "$slice": *n })));
+        }
+    }
+    if sliced.is_empty() {
+        return projection;
+    }
+    let mut projection = projection.unwrap_or_default();
+    for (name, slice) in sliced {
+        projection.insert(name, slice);
+    }
+    Some(projection)
+}
+
+/// The keys under which the fields in `schema` appear in the fetched documents.
+pub(crate) fn document_keys(schema: &Schema, escaped: bool) -> Vec<String> {
+    schema
+        .iter_names()
+        .map(|name| {
+            if escaped && needs_escaping(name) && name != RECORD_ID {
+                escape_field_name(name)
+            } else {
+                name.clone()
+            }
+        })
+        .collect()
+}
+
+/// Builds an inclusion projection of `columns`, without `_id` unless it is one of them.
+pub(crate) fn build_inclusion(columns: &[String]) -> Document {
+    let mut projection: Document = columns
+        .iter()
+        .map(|name| (name.clone(), Bson::Int64(1)))
+        .collect();
+    if !projection.contains_key("_id") {
+        projection.insert("_id", Bson::Int64(0));
+    }
+    projection
+}
+
+/// Builds an exclusion projection dropping `columns`.
+pub(crate) fn build_exclusion(columns: &[String]) -> Document {
+    columns
+        .iter()
+        .map(|name| (name.clone(), Bson::Int64(0)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema(names: &[&str]) -> Schema {
+        names
+            .iter()
+            .map(|name| Field::new(name, DataType::Utf8))
+            .collect()
+    }
+
+    #[test]
+    fn dotted_and_dollar_fields_are_fetched_by_their_literal_name() {
+        let schema = schema(&["a", "a.b", "$price"]);
+        assert_eq!(
+            build_projection(&schema, true),
+            doc! {
+                "a": 1_i64,
+                "a\u{FF0E}b": { "$getField": { "field": { "$literal": "a.b" } } },
+                "\u{FF04}price": { "$getField": { "field": { "$literal": "$price" } } },
+            }
+        );
+        assert_eq!(
+            document_keys(&schema, true),
+            ["a", "a\u{FF0E}b", "\u{FF04}price"]
+        );
+        // without escaping they are paths and operators, as mongodb reads them.
+        assert_eq!(
+            build_projection(&schema, false),
+            doc! { "a": 1_i64, "a.b": 1_i64, "$price": 1_i64 }
+        );
+        assert_eq!(document_keys(&schema, false), ["a", "a.b", "$price"]);
+        // only a leading `$` is escaped.
+        assert_eq!(escape_field_name("a$.b"), "a$\u{FF0E}b");
+    }
+
+    #[test]
+    fn arrays_are_sliced_in_any_projection() {
+        let list = DataType::List(Box::new(DataType::Int64));
+        let schema: Schema = [
+            Field::new("a", list.clone()),
+            Field::new("b", list.clone()),
+            Field::new("c.d", list),
+            Field::new("e", DataType::Int64),
+        ]
+        .into_iter()
+        .collect();
+        let slices: HashMap<_, _> = [("a", 3), ("b", -2), ("c.d", 1), ("unread", 1)]
+            .into_iter()
+            .map(|(name, n)| (name.to_string(), n))
+            .collect();
+        // fields that aren't read, or whose names need escaping, aren't sliced.
+        assert_eq!(
+            slice_arrays(None, &schema, &slices),
+            Some(doc! { "a": { "$slice": 3_i64 }, "b": { "$slice": -2_i64 } })
+        );
+        assert_eq!(
+            slice_arrays(Some(doc! { "a": 1_i64, "e": 1_i64 }), &schema, &slices),
+            Some(doc! { "a": { "$slice": 3_i64 }, "e": 1_i64, "b": { "$slice": -2_i64 } })
+        );
+        assert_eq!(
+            slice_arrays(Some(doc! { "e": 0_i64 }), &schema, &HashMap::new()),
+            Some(doc! { "e": 0_i64 })
+        );
+        assert_eq!(slice_arrays(None, &schema, &HashMap::new()), None);
+    }
+}
@@ -0,0 +1,99 @@
+use mongodb::bson::{doc, Bson, Document, Timestamp};
+use mongodb::error::{Error, ErrorKind};
+use mongodb::options::FindOptions;
+use mongodb::sync::Client;
+use polars::prelude::*;
+
+use crate::error::mongo_error;
+use crate::explain::find_command;
+
+/// Server error codes of a snapshot that is older than the oplog window.
+const SNAPSHOT_TOO_OLD: i32 = 239;
+const SNAPSHOT_UNAVAILABLE: i32 = 246;
+
+/// Runs a find reading the data as of `at_cluster_time`, passing the documents of each batch
+/// to `on_batch` as they arrive.
+///
+/// The driver can't set `atClusterTime` on a find, so the raw `find` and `getMore` commands
+/// are run in one explicit session, which the cursor is bound to. If `on_batch` fails, the
+/// server cursor is killed instead of being left open until it times out.
+pub(crate) fn find_at_cluster_time(
+    client: &Client,
+    db: &str,
+    collection: &str,
+    filter: Option<&Document>,
+    options: &FindOptions,
+    at_cluster_time: Timestamp,
+    mut on_batch: impl FnMut(Vec<Document>) -> PolarsResult<()>,
+) -> PolarsResult<()> {
+    let database = client.database(db);
+    let mut session = client.start_session(None).map_err(mongo_error)?;
+
+    let mut find = find_command(collection, filter, options)?;
+    find.insert(
+        "readConcern",
+        doc! { "level": "snapshot", "atClusterTime": Bson::Timestamp(at_cluster_time) },
+    );
+    let mut res = database
+        .run_command_with_session(find, None, &mut session)
+        .map_err(|err| snapshot_error(err, at_cluster_time))?;
+
+    loop {
+        let cursor = res.get_document_mut("cursor").map_err(|_| {
+            PolarsError::ComputeError(format!("find on '{}' returned no cursor", collection).into())
+        })?;
+        let id = cursor.get_i64("id").unwrap_or(0);
+        let batch = match cursor
+            .remove("firstBatch")
+            .or_else(|| cursor.remove("nextBatch"))
+        {
+            Some(Bson::Array(batch)) => batch,
+            _ => {
+                return Err(PolarsError::ComputeError(
+                    format!("cursor on '{}' returned no batch", collection).into(),
+                ))
+            }
+        };
+        let docs = batch
+            .into_iter()
+            .filter_map(|doc| match doc {
+                Bson::Document(doc) => Some(doc),
+                _ => None,
+            })
+            .collect();
+        if let Err(err) = on_batch(docs) {
+            if id != 0 {
+                // the read fails either way, a cursor that can't be killed times out.
+                let kill = doc! { "killCursors": collection, "cursors": [id] };
+                let _ = database.run_command_with_session(kill, None, &mut session);
+            }
+            return Err(err);
+        }
+
+        if id == 0 {
+            return Ok(());
+        }
+        let mut get_more = doc! { "getMore": id, "collection": collection };
+        if let Some(batch_size) = options.batch_size {
+            get_more.insert("batchSize", batch_size as i64);
+        }
+        res = database
+            .run_command_with_session(get_more, None, &mut session)
+            .map_err(|err| snapshot_error(err, at_cluster_time))?;
+    }
+}
+
+fn snapshot_error(err: Error, at_cluster_time: Timestamp) -> PolarsError {
+    match err.kind.as_ref() {
+        ErrorKind::Command(e) if e.code == SNAPSHOT_TOO_OLD || e.code == SNAPSHOT_UNAVAILABLE => {
+            PolarsError::ComputeError(
+                format!(
+                    "cluster time {:?} is outside of the oplog window of the server: {}",
+                    at_cluster_time, e.message
+                )
+                .into(),
+            )
+        }
+        _ => mongo_error(err),
+    }
+}
@@ -0,0 +1,117 @@
+use mongodb::error::ErrorKind;
+use mongodb::options::{InsertManyOptions, WriteConcern};
+use mongodb::sync::Client;
+use polars::prelude::*;
+
+use crate::conversion::dataframe_to_documents;
+use crate::error::mongo_error;
+use crate::parse_connection_str;
+
+/// Options of `write_mongo_collection`.
+#[derive(Debug, Clone)]
+pub struct MongoWriteOptions {
+    /// mongodb style connection string. `mongodb://<user>:<password>@host.domain`
+    pub connection_str: String,
+    /// the name of the mongodb database
+    pub db: String,
+    /// the name of the mongodb collection
+    pub collection: String,
+    /// rows converted and inserted at a time, so that only one batch of documents is held in
+    /// memory. Defaults to `1000`.
+    pub batch_size: usize,
+    /// stop at the first document that fails to be inserted. Unordered inserts, the default,
+    /// insert the other documents and let the server insert them in parallel.
+    pub ordered: bool,
+    /// leave null values out of the documents instead of writing them as `null`.
+    pub skip_nulls: bool,
+    /// durability the inserts wait for, e.g. `w: majority` with `j: true`. Defaults to the
+    /// write concern of the connection string, acknowledged by the primary unless it sets one.
+    pub write_concern: Option<WriteConcern>,
+}
+
+impl Default for MongoWriteOptions {
+    fn default() -> Self {
+        MongoWriteOptions {
+            connection_str: String::new(),
+            db: String::new(),
+            collection: String::new(),
+            batch_size: 1000,
+            ordered: false,
+            skip_nulls: false,
+            write_concern: None,
+        }
+    }
+}
+
+/// A row that failed to be inserted, e.g. because of a duplicate `_id` or a failed validation.
+#[derive(Debug, Clone)]
+pub struct WriteError {
+    /// index of the row in the written frame.
+    pub row: usize,
+    /// server error code.
+    pub code: i32,
+    /// server error message.
+    pub message: String,
+}
+
+/// The outcome of `write_mongo_collection`.
+#[derive(Debug, Clone, Default)]
+pub struct WriteSummary {
+    /// number of inserted rows.
+    pub inserted: usize,
+    /// the rows that failed to be inserted. In ordered mode the write stops at the first one.
+    pub errors: Vec<WriteError>,
+}
+
+/// Inserts the rows of `df` into a collection, one `insertMany` per batch of rows. The rows
+/// are converted with `dataframe_to_documents`.
+///
+/// Rows the server rejects are reported in the summary instead of failing the write, other
+/// errors, such as a lost connection or a write concern that can't be satisfied, fail it.
+pub fn write_mongo_collection(
+    df: &DataFrame,
+    options: &MongoWriteOptions,
+) -> PolarsResult<WriteSummary> {
+    let client = Client::with_options(parse_connection_str(&options.connection_str)?)
+        .map_err(mongo_error)?;
+    let collection = client
+        .database(&options.db)
+        .collection::<mongodb::bson::Document>(&options.collection);
+    let insert_options = InsertManyOptions::builder()
+        .ordered(options.ordered)
+        .write_concern(options.write_concern.clone())
+        .build();
+
+    let mut summary = WriteSummary::default();
+    let batch_size = options.batch_size.max(1);
+    for offset in (0..df.height()).step_by(batch_size) {
+        let batch = df.slice(offset as i64, batch_size);
+        let docs = dataframe_to_documents(&batch, options.skip_nulls)?;
+        let n_docs = docs.len();
+        match collection.insert_many(docs, insert_options.clone()) {
+            Ok(res) => summary.inserted += res.inserted_ids.len(),
+            Err(err) => match err.kind.as_ref() {
+                ErrorKind::BulkWrite(failure) if failure.write_concern_error.is_none() => {
+                    let errors = failure.write_errors.iter().flatten();
+                    let n_errors = failure.write_errors.as_ref().map_or(0, |e| e.len());
+                    // an ordered insert stops at the first error, the documents before it
+                    // are inserted.
+                    summary.inserted += match options.ordered {
+                        true => errors.clone().map(|e| e.index).min().unwrap_or(n_docs),
+                        false => n_docs - n_errors,
+                    };
+                    summary.errors.extend(errors.map(|e| WriteError {
+                        row: offset + e.index,
+                        code: e.code,
+                        message: e.message.clone(),
+                    }));
+                    if options.ordered {
+                        break;
+                    }
+                }
+                _ => return Err(mongo_error(err)),
+            },
+        }
+    }
+    Ok(summary)
+}